@@ -0,0 +1,167 @@
+use super::{
+    config::HistoryMode,
+    database::DeployState,
+    repo::{CommitHash, Repo},
+};
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+const CACHE_DIR: &str = "cepler-cache";
+const LAST_CHANGED: &str = "last_changed";
+
+/// Results of expensive history walks persisted in `.git/cepler-cache`.
+///
+/// All entries are keyed by commit hashes (plus a hash of everything else the result
+/// depends on) so they never go stale. Entries are written back when the cache is dropped.
+pub struct Cache {
+    dir: Option<PathBuf>,
+    last_changed: RefCell<Option<Entries<(CommitHash, String)>>>,
+    states: RefCell<HashMap<String, Entries<DeployState>>>,
+}
+
+struct Entries<T> {
+    map: HashMap<String, T>,
+    dirty: bool,
+}
+
+impl<T: Serialize + DeserializeOwned> Entries<T> {
+    fn load(path: &Path) -> Self {
+        // A missing or corrupt cache file just means starting over.
+        let map = fs::read(path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        Self { map, dirty: false }
+    }
+
+    fn save(&mut self, path: &Path) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let tmp = PathBuf::from(format!("{}.{}.tmp", path.display(), std::process::id()));
+        fs::write(&tmp, serde_json::to_vec(&self.map)?)?;
+        fs::rename(&tmp, path)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl Cache {
+    pub fn open(repo: &Repo) -> Self {
+        Self::with_dir(Some(repo.git_dir().join(CACHE_DIR)))
+    }
+
+    /// A cache that never stores anything.
+    pub fn disabled() -> Self {
+        Self::with_dir(None)
+    }
+
+    fn with_dir(dir: Option<PathBuf>) -> Self {
+        Self {
+            dir,
+            last_changed: RefCell::new(None),
+            states: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Removes all cached entries of `repo`.
+    pub fn clear(repo: &Repo) -> Result<bool> {
+        let dir = repo.git_dir().join(CACHE_DIR);
+        if !dir.exists() {
+            return Ok(false);
+        }
+        fs::remove_dir_all(&dir).with_context(|| format!("Couldn't remove '{}'", dir.display()))?;
+        Ok(true)
+    }
+
+    /// The commit that last changed `path` as seen from `commit` following `history`.
+    pub fn last_changed<F>(
+        &self,
+        history: HistoryMode,
+        commit: &CommitHash,
+        path: &Path,
+        f: F,
+    ) -> Result<(CommitHash, String)>
+    where
+        F: FnOnce() -> Result<(CommitHash, String)>,
+    {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return f(),
+        };
+        let key = format!("{}:{}:{}", history, commit, path.display());
+        let mut entries = self.last_changed.borrow_mut();
+        let entries = entries.get_or_insert_with(|| Entries::load(&dir.join(LAST_CHANGED)));
+        if let Some(ret) = entries.map.get(&key) {
+            return Ok(ret.clone());
+        }
+        let ret = f()?;
+        entries.map.insert(key, ret.clone());
+        entries.dirty = true;
+        Ok(ret)
+    }
+
+    /// The state of `env` at `commit`. `inputs` must capture everything
+    /// besides the commit that the state is derived from.
+    pub fn state<F>(
+        &self,
+        env: &str,
+        commit: &CommitHash,
+        inputs: &str,
+        f: F,
+    ) -> Result<DeployState>
+    where
+        F: FnOnce() -> Result<DeployState>,
+    {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return f(),
+        };
+        let key = format!("{}:{:x}", commit, Sha256::digest(inputs.as_bytes()));
+        let mut states = self.states.borrow_mut();
+        let entries = states
+            .entry(env.to_string())
+            .or_insert_with(|| Entries::load(&dir.join(format!("{}.states", env))));
+        if let Some(state) = entries.map.get(&key) {
+            return Ok(state.clone());
+        }
+        let state = f()?;
+        entries.map.insert(key, state.clone());
+        entries.dirty = true;
+        Ok(state)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let dirty = self.last_changed.borrow().as_ref().map(|e| e.dirty) == Some(true)
+            || self.states.borrow().values().any(|e| e.dirty);
+        if !dirty {
+            return Ok(());
+        }
+        fs::create_dir_all(dir)?;
+        if let Some(entries) = self.last_changed.borrow_mut().as_mut() {
+            entries.save(&dir.join(LAST_CHANGED))?;
+        }
+        for (env, entries) in self.states.borrow_mut().iter_mut() {
+            entries.save(&dir.join(format!("{}.states", env)))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Cache {
+    fn drop(&mut self) {
+        // The cache only speeds things up, failing to persist it must not fail the command.
+        let _ = self.save();
+    }
+}
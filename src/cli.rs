@@ -1,35 +1,99 @@
 use super::{
     concourse::{self},
     config::*,
-    database::Database,
+    database::{serialize_idents, FileDiff, FileIdent},
     repo::*,
-    workspace::Workspace,
+    validate::validate as validate_config,
+    workspace::{
+        CheckResult, ComparedFile, EnvironmentStatus, HistoryOptions, RecordOptions, StateId,
+        Workspace,
+    },
 };
 use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
 use clap::{clap_app, crate_version, App, ArgMatches};
-use std::path::Path;
+use serde::Serialize;
+use std::{
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
 
 fn app() -> App<'static, 'static> {
     let app = clap_app!(cepler =>
         (version: crate_version!())
         (@setting VersionlessSubcommands)
         (@setting SubcommandRequiredElseHelp)
-        (@arg CONFIG_FILE: -c --("config") env("CEPLER_CONF") default_value("cepler.yml") "Cepler config file")
+        (@arg REPO: --("repo") +takes_value env("CEPLER_REPO") conflicts_with("CLONE_DIR") "Path to the repository. Discovered from the current directory if not set")
+        (@arg CONFIG_FILE: -c --("config") env("CEPLER_CONF") default_value("cepler.yml") "Cepler config file relative to the repository root")
         (@arg IGNORE_QUEUE: --("ignore-queue") "Ignore the propagation queue")
-        (@arg GATES_FILE: -g --("gates") +takes_value env("CEPLER_GATES") "Cepler gate file")
+        (@arg STATE_STORE: --("state-store") +takes_value possible_values(&["tree", "branch", "notes"]) env("CEPLER_STATE_STORE") "Where to keep the state of the environments. Overrides 'state_store' in the config file")
+        (@arg STATE_REF: --("state-ref") +takes_value env("CEPLER_STATE_REF") "Branch or notes ref of the state store. Overrides 'state_ref' in the config file")
+        (@arg NO_CACHE: --("no-cache") "Don't use the state cache in .git/cepler-cache")
+        (@arg GATES_FILE: -g --("gates") +takes_value env("CEPLER_GATES") "Cepler gate file relative to the repository root")
         (@arg GATES_BRANCH: --("gates-branch") +takes_value requires_all(&["GATES_FILE"]) env("GATES_BRANCH") "Branch to find the gate file")
-        (@arg CLONE_DIR: --("clone") +takes_value requires_all(&["GIT_URL", "GIT_PRIVATE_KEY"]) "Clone the repository into <dir>. Pulls latest changes if already present.")
+        (@arg CLONE_DIR: --("clone") +takes_value requires_all(&["GIT_URL"]) "Clone the repository into <dir>. Pulls latest changes if already present.")
         (@arg GIT_URL: --("git-url") +takes_value env("GIT_URL") "Remote url for --clone option")
         (@arg GIT_PRIVATE_KEY: --("git-private-key") +takes_value env("GIT_PRIVATE_KEY") "Private key for --clone option")
+        (@arg GIT_PRIVATE_KEY_PATH: --("git-private-key-path") +takes_value conflicts_with("GIT_PRIVATE_KEY") env("GIT_PRIVATE_KEY_PATH") "Path to the private key for --clone option")
+        (@arg GIT_PASSPHRASE: --("git-passphrase") +takes_value env("GIT_PASSPHRASE") "Passphrase of the private key")
+        (@arg GIT_USERNAME: --("git-username") +takes_value env("GIT_USERNAME") "Username for https remotes")
+        (@arg GIT_PASSWORD: --("git-password") +takes_value env("GIT_PASSWORD") "Password for https remotes")
+        (@arg GIT_TOKEN: --("git-token") +takes_value conflicts_with("GIT_PASSWORD") env("GIT_TOKEN") "Access token for https remotes")
+        (@arg KNOWN_HOSTS: --("known-hosts") +takes_value env("CEPLER_KNOWN_HOSTS") "known_hosts file to verify the host key of ssh remotes against. Defaults to ~/.ssh/known_hosts")
+        (@arg INSECURE_SKIP_HOST_KEY_CHECK: --("insecure-skip-host-key-check") conflicts_with("KNOWN_HOSTS") "Don't verify the host key of ssh remotes")
+        (@arg GIT_DEPTH: --("depth") +takes_value requires_all(&["CLONE_DIR"]) env("GIT_DEPTH") "Only clone and pull this many commits of history for --clone option")
         (@arg GIT_BRANCH: --("git-branch") +takes_value default_value("main") env("GIT_BRANCH") "Branch for --clone option")
+        (@arg OUTPUT: -o --("output") +takes_value possible_values(&["text", "json"]) default_value("text") env("CEPLER_OUTPUT") "Output format")
         (@subcommand check =>
           (about: "Check wether the environment needs deploying. Exit codes: 0 - needs deploying; 1 - internal error; 2 - nothing to deploy")
           (@arg ENVIRONMENT: -e --("environment") env("CEPLER_ENVIRONMENT") +required +takes_value "The cepler environment")
         )
+        (@subcommand preview =>
+          (about: "Explain what the next record of the environment would deploy without changing any files")
+          (@arg ENVIRONMENT: -e --("environment") env("CEPLER_ENVIRONMENT") +required +takes_value "The cepler environment")
+        )
+        (@subcommand explain =>
+          (about: "Explain why a file is part of the state of the environment")
+          (@arg ENVIRONMENT: -e --("environment") env("CEPLER_ENVIRONMENT") +required +takes_value "The cepler environment")
+          (@arg FILE: +required "Path of the file relative to the root of the repository")
+        )
         (@subcommand ls =>
           (about: "List all files relevent to a given environment")
           (@arg ENVIRONMENT: -e --("environment") env("CEPLER_ENVIRONMENT") +required +takes_value "The cepler environment")
         )
+        (@subcommand diff =>
+          (about: "Show the content changes between the recorded state of an environment and the state prepare would produce")
+          (@arg ENVIRONMENT: -e --("environment") env("CEPLER_ENVIRONMENT") +required +takes_value "The cepler environment")
+          (@arg STAT: --("stat") conflicts_with("NAME_ONLY") "Only show the number of changed lines per file")
+          (@arg NAME_ONLY: --("name-only") "Only show the names of the changed files")
+          (@arg COLOR: --("color") +takes_value possible_values(&["auto", "always", "never"]) default_value("auto") "When to color the output")
+        )
+        (@subcommand compare =>
+          (about: "Compare the recorded states of two environments")
+          (@arg FIRST: +required "The first environment")
+          (@arg SECOND: +required "The second environment")
+          (@arg DIFF: --("diff") "Show the content changes from the first to the second environment")
+          (@arg COLOR: --("color") +takes_value possible_values(&["auto", "always", "never"]) default_value("auto") "When to color the output")
+        )
+        (@subcommand impact =>
+          (about: "List the environments the changes in a range of commits would deploy to")
+          (@arg RANGE: +required "The commits to analyse, eg. 'main..feature' or a single commit")
+          (@arg FORMAT: --("format") +takes_value possible_values(&["text", "markdown"]) default_value("text") "The format of the report")
+        )
+        (@subcommand graph =>
+          (about: "Print the environments and how they propagate to each other as a graph")
+          (@arg FORMAT: --("format") +takes_value possible_values(&["dot", "mermaid"]) default_value("dot") "Graphviz DOT or Mermaid")
+        )
+        (@subcommand status =>
+          (about: "Show the recorded state and pending changes of all environments")
+        )
+        (@subcommand history =>
+          (about: "List the recorded versions of an environment from the git history of its state file")
+          (@arg ENVIRONMENT: -e --("environment") env("CEPLER_ENVIRONMENT") +required +takes_value "The cepler environment")
+          (@arg SINCE: --("since") +takes_value "Only list versions recorded since <date> (YYYY-MM-DD or RFC 3339)")
+          (@arg LIMIT: --("limit") +takes_value "List at most <limit> versions")
+        )
         (@subcommand latest =>
           (about: "Return the commit hash of the lastest record")
           (@arg ENVIRONMENT: -e --("environment") env("CEPLER_ENVIRONMENT") +required +takes_value "The cepler environment")
@@ -39,9 +103,17 @@ fn app() -> App<'static, 'static> {
           (@arg ENVIRONMENT: -e --("environment") env("CEPLER_ENVIRONMENT") +required +takes_value "The cepler environment")
           (@arg NO_COMMIT: --("no-commit") "Don't commit the new state")
           (@arg RESET_HEAD: --("reset-head") "Checkout files to head after committing the state")
-          (@arg PUSH: --("push") requires_all(&["RESET_HEAD", "GIT_URL", "GIT_PRIVATE_KEY"]) "Push head to remote")
+          (@arg PUSH: --("push") requires_all(&["RESET_HEAD", "GIT_URL"]) "Push head to remote")
+          (@arg PUSH_RETRIES: --("push-retries") +takes_value default_value("3") env("CEPLER_PUSH_RETRIES") "How often to fetch, rebase and push again if the remote moved on while pushing")
           (@arg GIT_URL: --("git-url") +takes_value env("GIT_URL") "Remote url for --clone option")
-          (@arg GIT_PRIVATE_KEY: --("git-private-key") +takes_value env("GIT_PRIVATE_KEY") "Private key for --clone option")
+          (@arg GIT_PRIVATE_KEY: --("git-private-key") +takes_value env("GIT_PRIVATE_KEY") "Private key for --push option")
+          (@arg GIT_PRIVATE_KEY_PATH: --("git-private-key-path") +takes_value conflicts_with("GIT_PRIVATE_KEY") env("GIT_PRIVATE_KEY_PATH") "Path to the private key for --push option")
+          (@arg GIT_PASSPHRASE: --("git-passphrase") +takes_value env("GIT_PASSPHRASE") "Passphrase of the private key")
+          (@arg GIT_USERNAME: --("git-username") +takes_value env("GIT_USERNAME") "Username for https remotes")
+          (@arg GIT_PASSWORD: --("git-password") +takes_value env("GIT_PASSWORD") "Password for https remotes")
+          (@arg GIT_TOKEN: --("git-token") +takes_value conflicts_with("GIT_PASSWORD") env("GIT_TOKEN") "Access token for https remotes")
+          (@arg KNOWN_HOSTS: --("known-hosts") +takes_value env("CEPLER_KNOWN_HOSTS") "known_hosts file to verify the host key of ssh remotes against. Defaults to ~/.ssh/known_hosts")
+          (@arg INSECURE_SKIP_HOST_KEY_CHECK: --("insecure-skip-host-key-check") conflicts_with("KNOWN_HOSTS") "Don't verify the host key of ssh remotes")
           (@arg GIT_BRANCH: --("git-branch") +takes_value default_value("main") env("GIT_BRANCH") "Branch for --clone option")
         )
         (@subcommand prepare =>
@@ -52,8 +124,29 @@ fn app() -> App<'static, 'static> {
         (@subcommand reproduce =>
           (about: "Reproduce workspace according to last recorded state")
           (@arg ENVIRONMENT: -e --("environment") env("CEPLER_ENVIRONMENT") +required +takes_value "The cepler environment")
+          (@arg VERSION: --("version") +takes_value "Reproduce an earlier recorded version instead")
           (@arg FORCE_CLEAN: --("force-clean") "Delete all files not referenced in cepler.yml")
         )
+        (@subcommand rollback =>
+          (about: "Reproduce an earlier recorded version. The next record of the environment persists it as a new version")
+          (@arg ENVIRONMENT: -e --("environment") env("CEPLER_ENVIRONMENT") +required +takes_value "The cepler environment")
+          (@arg TO_VERSION: --("to-version") +required +takes_value "The version to roll back to")
+          (@arg FORCE_CLEAN: --("force-clean") "Delete all files not referenced in cepler.yml")
+        )
+        (@subcommand doctor =>
+          (about: "Find commits referenced by the recorded states that no longer exist. Exit codes: 0 - no dangling commits (left); 1 - dangling commits found")
+          (@arg FIX: --("fix") "Re-anchor dangling commits to existing commits with identical content and commit the repaired states")
+        )
+        (@subcommand validate =>
+          (about: "Validate the config file. Exit codes: 0 - config is valid; 1 - problems were found")
+        )
+        (@subcommand cache =>
+          (@setting SubcommandRequiredElseHelp)
+          (about: "Manage the state cache in .git/cepler-cache")
+          (@subcommand clear =>
+            (about: "Remove all cached states")
+          )
+        )
         (@subcommand concourse =>
          (@setting SubcommandRequiredElseHelp)
          (about: "Subcommand for concourse integration")
@@ -74,54 +167,106 @@ fn app() -> App<'static, 'static> {
     app
 }
 
-pub fn run() -> Result<()> {
+pub fn run() -> Result<ExitCode> {
     let matches = app().get_matches();
-    let ignore_queue = matches.is_present("IGNORE_QUEUE");
-    if let Some(dir) = matches.value_of("CLONE_DIR") {
-        let conf = GitConfig {
-            url: matches.value_of("GIT_URL").unwrap().to_string(),
-            branch: matches.value_of("GIT_BRANCH").unwrap().to_string(),
-            gates_branch: matches.value_of("GATES_BRANCH").map(|b| b.to_string()),
-            private_key: matches.value_of("GIT_PRIVATE_KEY").unwrap().to_string(),
-            dir: dir.to_string(),
-        };
-        let path = std::path::Path::new(&dir);
+    let repo_path = matches
+        .value_of("CLONE_DIR")
+        .or_else(|| matches.value_of("REPO"))
+        .map(Path::new);
+    if let Some(conf) = clone_config(&matches)? {
+        let path = Path::new(matches.value_of("CLONE_DIR").unwrap());
         if !path.exists() || path.read_dir()?.next().is_none() {
             Repo::clone(conf)?;
-            std::env::set_current_dir(dir)?;
         } else {
-            std::env::set_current_dir(dir)?;
-            Repo::open(None)?.pull(conf)?;
+            Repo::open(Some(path), None)?.pull(conf)?;
         }
     }
 
+    let output = Output::from_matches(&matches);
     match matches.subcommand() {
         ("ls", Some(sub_matches)) => ls(
             sub_matches,
-            conf_from_matches(&matches)?,
-            gates_from_matches(&matches)?,
-            ignore_queue,
+            workspace_from_matches(&matches, repo_path)?,
+            output,
         ),
         ("check", Some(sub_matches)) => check(
             sub_matches,
-            conf_from_matches(&matches)?,
-            gates_from_matches(&matches)?,
-            ignore_queue,
+            workspace_from_matches(&matches, repo_path)?,
+            output,
+        ),
+        ("preview", Some(sub_matches)) => preview(
+            sub_matches,
+            workspace_from_matches(&matches, repo_path)?,
+            output,
+        ),
+        ("explain", Some(sub_matches)) => explain(
+            sub_matches,
+            workspace_from_matches(&matches, repo_path)?,
+            output,
         ),
         ("prepare", Some(sub_matches)) => prepare(
             sub_matches,
-            conf_from_matches(&matches)?,
-            gates_from_matches(&matches)?,
-            ignore_queue,
+            workspace_from_matches(&matches, repo_path)?,
+            output,
+        ),
+        ("reproduce", Some(sub_matches)) => reproduce(
+            sub_matches,
+            workspace_from_matches(&matches, repo_path)?,
+            output,
+        ),
+        ("rollback", Some(sub_matches)) => rollback(
+            sub_matches,
+            workspace_from_matches(&matches, repo_path)?,
+            output,
         ),
-        ("reproduce", Some(sub_matches)) => reproduce(sub_matches, conf_from_matches(&matches)?),
         ("record", Some(sub_matches)) => record(
             sub_matches,
-            conf_from_matches(&matches)?,
-            gates_from_matches(&matches)?,
-            ignore_queue,
+            workspace_from_matches(&matches, repo_path)?,
+            output,
+        ),
+        ("diff", Some(sub_matches)) => diff(
+            sub_matches,
+            workspace_from_matches(&matches, repo_path)?,
+            output,
         ),
-        ("latest", Some(sub_matches)) => latest(sub_matches, conf_from_matches(&matches)?),
+        ("compare", Some(sub_matches)) => compare(
+            sub_matches,
+            workspace_from_matches(&matches, repo_path)?,
+            output,
+        ),
+        ("impact", Some(sub_matches)) => impact(
+            sub_matches,
+            workspace_from_matches(&matches, repo_path)?,
+            output,
+        ),
+        ("graph", Some(sub_matches)) => graph(
+            sub_matches,
+            workspace_from_matches(&matches, repo_path)?,
+            output,
+        ),
+        ("status", Some(_)) => status(workspace_from_matches(&matches, repo_path)?, output),
+        ("history", Some(sub_matches)) => history(
+            sub_matches,
+            workspace_from_matches(&matches, repo_path)?,
+            output,
+        ),
+        ("latest", Some(sub_matches)) => latest(
+            sub_matches,
+            workspace_from_matches(&matches, repo_path)?,
+            output,
+        ),
+        ("doctor", Some(sub_matches)) => doctor(
+            sub_matches,
+            workspace_from_matches(&matches, repo_path)?,
+            output,
+        ),
+        ("validate", Some(_)) => {
+            validate(matches.value_of("CONFIG_FILE").unwrap(), repo_path, output)
+        }
+        ("cache", Some(sub_matches)) => match sub_matches.subcommand() {
+            ("clear", Some(_)) => cache_clear(repo_path, output),
+            _ => unreachable!(),
+        },
         ("concourse", Some(sub_matches)) => match sub_matches.subcommand() {
             ("check", Some(_)) => concourse_check(),
             ("ci_in", Some(matches)) => concourse_in(matches),
@@ -132,180 +277,905 @@ pub fn run() -> Result<()> {
     }
 }
 
-fn check(
-    matches: &ArgMatches,
-    (config, config_path): (Config, String),
-    gates: Option<GatesConfig>,
-    ignore_queue: bool,
-) -> Result<()> {
+/// Selected via `--output`. In `json` mode every command prints exactly one json
+/// document to stdout and all human readable messages go to stderr.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Output {
+    Text,
+    Json,
+}
+
+impl Output {
+    fn from_matches(matches: &ArgMatches) -> Self {
+        match matches.value_of("OUTPUT") {
+            Some("json") => Output::Json,
+            _ => Output::Text,
+        }
+    }
+
+    fn json<T: Serialize>(self, value: &T) -> Result<()> {
+        println!("{}", serde_json::to_string(value)?);
+        Ok(())
+    }
+
+    /// Adds the `warnings` collected while computing `value` to the json object.
+    fn json_with_warnings<T: Serialize>(self, value: &T, warnings: Vec<String>) -> Result<()> {
+        #[derive(Serialize)]
+        struct WithWarnings<'a, T> {
+            #[serde(flatten)]
+            value: &'a T,
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            warnings: Vec<String>,
+        }
+
+        self.json(&WithWarnings { value, warnings })
+    }
+
+    fn warn_force_clean(self, force_clean: bool) {
+        if !force_clean {
+            return;
+        }
+        match self {
+            Output::Text => println!("WARNING removing all non-cepler specified files"),
+            Output::Json => eprintln!("WARNING removing all non-cepler specified files"),
+        }
+    }
+}
+
+fn print_warnings(ws: &Workspace) -> Vec<String> {
+    let warnings = ws.take_warnings();
+    for warning in warnings.iter() {
+        eprintln!("WARNING {}", warning);
+    }
+    warnings
+}
+
+fn check(matches: &ArgMatches, ws: Workspace, output: Output) -> Result<ExitCode> {
+    /// Keeps the shape of `CheckResult` when there is nothing new to deploy.
+    #[derive(Serialize)]
+    struct NothingNew {
+        state_id: Option<StateId>,
+        diffs: Vec<FileDiff>,
+    }
+
     let env = matches.value_of("ENVIRONMENT").unwrap();
-    let gate = if let Some(gates) = gates {
-        gates.get_gate(env)?
-    } else {
-        None
-    };
-    let ws = Workspace::new(&config.scope, config_path.clone(), ignore_queue)?;
-    let env = config.environments.get(env).context(format!(
-        "Environment '{}' not found in config '{}'",
-        env, config_path
-    ))?;
-    match ws.check(env, gate)? {
+    let check = ws.check(env)?;
+    let warnings = print_warnings(&ws);
+    if output == Output::Json {
+        match check.as_ref() {
+            Some(check) => output.json_with_warnings(check, warnings)?,
+            None => output.json_with_warnings(
+                &NothingNew {
+                    state_id: None,
+                    diffs: Vec::new(),
+                },
+                warnings,
+            )?,
+        }
+        return Ok(if check.is_some() {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::from(2)
+        });
+    }
+    match check {
         None => {
             println!("Nothing new to deploy");
-            std::process::exit(2);
+            Ok(ExitCode::from(2))
         }
-        Some((state_id, _)) => {
+        Some(CheckResult { state_id, diffs }) => {
+            for diff in diffs.iter() {
+                eprintln!("{}", diff);
+            }
             println!(
                 "Found new state to deploy - trigger commit {}",
                 state_id.head_commit
             );
+            Ok(ExitCode::SUCCESS)
         }
     }
-    Ok(())
 }
 
-fn ls(
-    matches: &ArgMatches,
-    (config, config_path): (Config, String),
-    gates: Option<GatesConfig>,
-    ignore_queue: bool,
-) -> Result<()> {
+fn preview(matches: &ArgMatches, ws: Workspace, output: Output) -> Result<ExitCode> {
     let env = matches.value_of("ENVIRONMENT").unwrap();
-    let gate = if let Some(gates) = gates {
-        gates.get_gate(env)?
-    } else {
-        None
-    };
-    let ws = Workspace::new(&config.scope, config_path.clone(), ignore_queue)?;
-    let env = config.environments.get(env).context(format!(
-        "Environment '{}' not found in config '{}'",
-        env, config_path
-    ))?;
-    for path in ws.ls(env, gate)? {
-        println!("{}", path);
-    }
-    Ok(())
-}
-fn prepare(
-    matches: &ArgMatches,
-    config: (Config, String),
-    gates: Option<GatesConfig>,
-    ignore_queue: bool,
-) -> Result<()> {
+    let preview = ws.preview(env)?;
+    let warnings = print_warnings(&ws);
+    if output == Output::Json {
+        output.json_with_warnings(&preview, warnings)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    for target in preview.upstreams.iter() {
+        println!(
+            "Propagating {} of '{}' [{}] - {}",
+            target.state,
+            target.upstream,
+            &target.head_commit[..7],
+            target.reason
+        );
+    }
+    match preview.state_id {
+        None => println!("Nothing new to deploy"),
+        Some(state_id) => {
+            println!(
+                "Recording would deploy version {} of '{}' - trigger commit {}",
+                state_id.version, preview.environment, state_id.head_commit
+            );
+            for diff in preview.diffs.iter() {
+                println!("{}", diff);
+            }
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn explain(matches: &ArgMatches, ws: Workspace, output: Output) -> Result<ExitCode> {
+    let env = matches.value_of("ENVIRONMENT").unwrap();
+    let explanation = ws.explain(env, matches.value_of("FILE").unwrap())?;
+    let warnings = print_warnings(&ws);
+    if output == Output::Json {
+        output.json_with_warnings(&explanation, warnings)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    for glob in explanation.latest.iter() {
+        println!("Matches latest glob '{}'", glob);
+    }
+    if let Some(ignored_by) = explanation.ignored_by.as_ref() {
+        println!("Ignored as it matches '{}'", ignored_by);
+    }
+    for upstream in explanation.upstreams.iter() {
+        if upstream.patterns.is_empty() {
+            continue;
+        }
+        for glob in upstream.patterns.iter() {
+            println!(
+                "Matches propagated glob '{}' of '{}'",
+                glob, upstream.upstream
+            );
+        }
+        match (upstream.state.as_ref(), upstream.from_commit.as_ref()) {
+            (None, _) => println!("'{}' wasn't recorded yet", upstream.upstream),
+            (Some(target), None) => println!(
+                "'{}' propagates {} [{}] which doesn't contain the file",
+                upstream.upstream,
+                target.state,
+                &target.head_commit[..7]
+            ),
+            (Some(target), Some(from_commit)) => println!(
+                "'{}' propagates {} [{}] ({}) with the file from [{}]",
+                upstream.upstream,
+                target.state,
+                &target.head_commit[..7],
+                target.reason,
+                &from_commit[..7]
+            ),
+        }
+        if let Some(shadowed_by) = upstream.shadowed_by.as_ref() {
+            println!(
+                "Shadowed by '{}' which takes precedence over '{}'",
+                shadowed_by, upstream.upstream
+            );
+        }
+    }
+    match (
+        explanation.source.as_ref(),
+        explanation.from_commit.as_ref(),
+        explanation.message.as_ref(),
+    ) {
+        (Some(source), Some(from_commit), Some(message)) => {
+            println!(
+                "{} is taken from {} - [{}] - {}",
+                explanation.file,
+                source,
+                &from_commit[..7],
+                message
+            );
+            if let Some(change) = explanation.change {
+                println!(
+                    "[{}] is the oldest commit with the current content - {}",
+                    &from_commit[..7],
+                    change
+                );
+            }
+        }
+        _ => println!(
+            "{} is not part of the state of '{}'",
+            explanation.file, explanation.environment
+        ),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn ls(matches: &ArgMatches, ws: Workspace, output: Output) -> Result<ExitCode> {
+    #[derive(Serialize)]
+    struct Ls {
+        #[serde(serialize_with = "serialize_idents")]
+        files: Vec<FileIdent>,
+    }
+
+    let env = matches.value_of("ENVIRONMENT").unwrap();
+    let files = ws.ls(env)?;
+    let warnings = print_warnings(&ws);
+    match output {
+        Output::Text => {
+            for ident in files {
+                println!("{}", ident.name());
+            }
+        }
+        Output::Json => output.json_with_warnings(&Ls { files }, warnings)?,
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn prepare(matches: &ArgMatches, ws: Workspace, output: Output) -> Result<ExitCode> {
     let env = matches.value_of("ENVIRONMENT").unwrap();
     let force_clean: bool = matches.is_present("FORCE_CLEAN");
-    if force_clean {
-        println!("WARNING removing all non-cepler specified files");
+    output.warn_force_clean(force_clean);
+    let result = ws.prepare(env, force_clean)?;
+    let warnings = print_warnings(&ws);
+    if output == Output::Json {
+        output.json_with_warnings(&result, warnings)?;
     }
-    let gate = if let Some(gates) = gates {
-        gates.get_gate(env)?
-    } else {
-        None
+    Ok(ExitCode::SUCCESS)
+}
+
+fn reproduce(matches: &ArgMatches, ws: Workspace, output: Output) -> Result<ExitCode> {
+    let env = matches.value_of("ENVIRONMENT").unwrap();
+    let force_clean: bool = matches.is_present("FORCE_CLEAN");
+    output.warn_force_clean(force_clean);
+    let result = match matches.value_of("VERSION") {
+        Some(version) => ws.reproduce_version(env, parse_version(version)?, force_clean)?,
+        None => ws.reproduce(env, force_clean)?,
     };
-    let env = config.0.environments.get(env).context(format!(
-        "Environment '{}' not found in config '{}'",
-        env, config.1
-    ))?;
-    let ws = Workspace::new(&config.0.scope, config.1, ignore_queue)?;
-    ws.prepare(env, gate, force_clean)?;
-    Ok(())
-}
-fn reproduce(matches: &ArgMatches, config: (Config, String)) -> Result<()> {
+    if output == Output::Json {
+        output.json(&result)?;
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn rollback(matches: &ArgMatches, ws: Workspace, output: Output) -> Result<ExitCode> {
     let env = matches.value_of("ENVIRONMENT").unwrap();
+    let version = parse_version(matches.value_of("TO_VERSION").unwrap())?;
     let force_clean: bool = matches.is_present("FORCE_CLEAN");
-    if force_clean {
-        println!("WARNING removing all non-cepler specified files");
-    }
-    let env = config.0.environments.get(env).context(format!(
-        "Environment '{}' not found in config '{}'",
-        env, config.1
-    ))?;
-    let ws = Workspace::new(&config.0.scope, config.1, false)?;
-    ws.reproduce(env, force_clean)?;
-    Ok(())
-}
-
-fn record(
-    matches: &ArgMatches,
-    config: (Config, String),
-    gates: Option<GatesConfig>,
-    ignore_queue: bool,
-) -> Result<()> {
+    output.warn_force_clean(force_clean);
+    let result = ws.rollback(env, version, force_clean)?;
+    eprintln!(
+        "Reproduced version {} of '{}' - the next record will persist it as a new version",
+        version, env
+    );
+    if output == Output::Json {
+        output.json(&result)?;
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn parse_version(version: &str) -> Result<u32> {
+    version
+        .parse()
+        .with_context(|| format!("Version '{}' is not a number", version))
+}
+
+fn record(matches: &ArgMatches, mut ws: Workspace, output: Output) -> Result<ExitCode> {
     let env = matches.value_of("ENVIRONMENT").unwrap();
-    let gate = if let Some(gates) = gates {
-        gates.get_gate(env)?
-    } else {
-        None
-    };
-    let commit = !matches.is_present("NO_COMMIT");
-    let reset = matches.is_present("RESET_HEAD");
-    let push = matches.is_present("PUSH");
-    let git_config = if push {
+    let push = if matches.is_present("PUSH") {
         Some(GitConfig {
             url: matches.value_of("GIT_URL").unwrap().to_string(),
             branch: matches.value_of("GIT_BRANCH").unwrap().to_string(),
             gates_branch: None,
-            private_key: matches.value_of("GIT_PRIVATE_KEY").unwrap().to_string(),
+            credentials: credentials_from_matches(matches)?,
             dir: String::new(),
+            depth: None,
         })
     } else {
         None
     };
-    let env = config.0.environments.get(env).context(format!(
-        "Environment '{}' not found in config '{}'",
-        env, config.1
-    ))?;
-    let mut ws = Workspace::new(&config.0.scope, config.1, ignore_queue)?;
-    ws.record_env(env, gate, commit, reset, git_config)?;
-    Ok(())
+    let options = RecordOptions {
+        commit: !matches.is_present("NO_COMMIT"),
+        reset_head: matches.is_present("RESET_HEAD"),
+        push,
+        push_retries: matches
+            .value_of("PUSH_RETRIES")
+            .unwrap()
+            .parse()
+            .context("--push-retries is not a number")?,
+    };
+    let pushing = options.push.is_some();
+    eprintln!("Recording current state");
+    let result = ws.record(env, options)?;
+    let warnings = print_warnings(&ws);
+    if let Some(version) = result.rollback_of {
+        eprintln!(
+            "Recorded rollback to version {} as version {}",
+            version, result.state_id.version
+        );
+    }
+    if result.committed {
+        eprintln!("Added commit to repository to persist state");
+    }
+    if pushing && !result.pushed {
+        eprintln!("... there was nothing new to push");
+    }
+    if output == Output::Json {
+        output.json_with_warnings(&result, warnings)?;
+    }
+    Ok(ExitCode::SUCCESS)
 }
 
-fn latest(matches: &ArgMatches, (config, config_file): (Config, String)) -> Result<()> {
+fn diff(matches: &ArgMatches, ws: Workspace, output: Output) -> Result<ExitCode> {
     let env = matches.value_of("ENVIRONMENT").unwrap();
-    let db = Database::open(&config.scope, &config_file, false)?;
-    if let Some((_, env)) = db.get_current_state(env) {
-        println!("{}", env.head_commit.clone().inner());
+    let diffs = ws.diff(env)?;
+    print_warnings(&ws);
+    if output == Output::Json {
+        output.json(&diffs)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    let color = color_from_matches(matches);
+    if matches.is_present("NAME_ONLY") {
+        for diff in diffs {
+            println!("{}", diff.file);
+        }
+    } else if matches.is_present("STAT") {
+        let width = diffs.iter().map(|d| d.file.len()).max().unwrap_or(0);
+        for diff in diffs.iter() {
+            println!(
+                " {:width$} | {:>4} {}{}",
+                diff.file,
+                diff.insertions + diff.deletions,
+                paint(color, "32", &"+".repeat(diff.insertions)),
+                paint(color, "31", &"-".repeat(diff.deletions)),
+                width = width
+            );
+        }
+        println!(
+            " {} file(s) changed, {} insertion(s)(+), {} deletion(s)(-)",
+            diffs.len(),
+            diffs.iter().map(|d| d.insertions).sum::<usize>(),
+            diffs.iter().map(|d| d.deletions).sum::<usize>()
+        );
+    } else {
+        for diff in diffs {
+            print_patch(&diff.patch, color);
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn compare(matches: &ArgMatches, ws: Workspace, output: Output) -> Result<ExitCode> {
+    let first = matches.value_of("FIRST").unwrap();
+    let second = matches.value_of("SECOND").unwrap();
+    let comparison = ws.compare(first, second, matches.is_present("DIFF"))?;
+    if output == Output::Json {
+        output.json(&comparison)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    let color = color_from_matches(matches);
+    let side = |env: &str, file: &ComparedFile| {
+        let source = if file.source == "latest" {
+            String::new()
+        } else {
+            format!(" from {}", file.source)
+        };
+        format!("{} is on {}{}", env, &file.from_commit[..7], source)
+    };
+    for file in comparison.files.iter() {
+        match (file.first.as_ref(), file.second.as_ref()) {
+            (Some(a), Some(b)) => println!(
+                "{} differs - {}, {}",
+                file.file,
+                side(first, a),
+                side(second, b)
+            ),
+            (Some(a), None) => println!("{} only in {} - {}", file.file, first, side(first, a)),
+            (None, Some(b)) => println!("{} only in {} - {}", file.file, second, side(second, b)),
+            (None, None) => unreachable!(),
+        }
+        if let Some(patch) = file.patch.as_ref() {
+            print_patch(patch, color);
+        }
+    }
+    println!(
+        "{} file(s) differ, {} identical",
+        comparison.files.len(),
+        comparison.identical
+    );
+    Ok(ExitCode::SUCCESS)
+}
+
+fn impact(matches: &ArgMatches, ws: Workspace, output: Output) -> Result<ExitCode> {
+    let range = matches.value_of("RANGE").unwrap();
+    let impact = ws.impact(range)?;
+    if output == Output::Json {
+        output.json(&impact)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    let short = |commit: &String| commit[..7].to_string();
+    let range = match impact.from.as_ref() {
+        Some(from) => format!("{}..{}", short(from), short(&impact.to)),
+        None => short(&impact.to),
+    };
+    if matches.value_of("FORMAT") == Some("markdown") {
+        let files = |files: &[String]| {
+            files
+                .iter()
+                .map(|f| format!("`{}`", f))
+                .collect::<Vec<_>>()
+                .join("<br>")
+        };
+        println!("### Deployment impact of `{}`", range);
+        println!();
+        println!("| Environment | Impact | Files |");
+        println!("| --- | --- | --- |");
+        for env in impact.environments.iter() {
+            if !env.immediate.is_empty() {
+                println!(
+                    "| {} | deploys immediately | {} |",
+                    env.name,
+                    files(&env.immediate)
+                );
+            }
+            for (upstream, propagated) in env.propagated.iter() {
+                println!(
+                    "| {} | propagated from {} | {} |",
+                    env.name,
+                    upstream,
+                    files(propagated)
+                );
+            }
+            if !env.affected() {
+                println!("| {} | not affected | |", env.name);
+            }
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+    println!("{} file(s) changed in {}", impact.files.len(), range);
+    for env in impact.environments.iter() {
+        if !env.immediate.is_empty() {
+            println!(
+                "'{}' deploys immediately: {}",
+                env.name,
+                env.immediate.join(", ")
+            );
+        }
+        for (upstream, propagated) in env.propagated.iter() {
+            println!(
+                "'{}' receives via propagation from '{}': {}",
+                env.name,
+                upstream,
+                propagated.join(", ")
+            );
+        }
+        if !env.affected() {
+            println!("'{}' is not affected", env.name);
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn graph(matches: &ArgMatches, ws: Workspace, output: Output) -> Result<ExitCode> {
+    let graph = ws.graph()?;
+    let warnings = print_warnings(&ws);
+    if output == Output::Json {
+        output.json_with_warnings(&graph, warnings)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    let label = |env: &EnvironmentStatus| {
+        let recorded = match (env.version, env.head_commit.as_ref()) {
+            (Some(version), Some(head_commit)) => {
+                format!("v{} [{}]", version, &head_commit[..7])
+            }
+            _ => "not recorded".to_string(),
+        };
+        let status = match env.blocked_by.as_ref() {
+            Some(upstream) => format!("waiting for {}", upstream),
+            None if env.pending => "pending changes".to_string(),
+            None => "up to date".to_string(),
+        };
+        vec![env.name.clone(), recorded, status]
+    };
+    if matches.value_of("FORMAT") == Some("mermaid") {
+        let id = |name: &str| {
+            graph
+                .nodes
+                .iter()
+                .position(|env| env.name == name)
+                .map(|idx| format!("env{}", idx))
+                .unwrap_or_else(|| name.to_string())
+        };
+        let escape = |lines: Vec<String>| {
+            lines
+                .iter()
+                .map(|l| l.replace('"', "#quot;"))
+                .collect::<Vec<_>>()
+                .join("<br>")
+        };
+        println!("graph LR");
+        for env in graph.nodes.iter() {
+            println!("  {}[\"{}\"]", id(&env.name), escape(label(env)));
+        }
+        for edge in graph.edges.iter() {
+            println!(
+                "  {} -->|\"{}\"| {}",
+                id(&edge.from),
+                escape(edge.files.clone()),
+                id(&edge.to)
+            );
+        }
+    } else {
+        let escape = |lines: Vec<String>| {
+            lines
+                .iter()
+                .map(|l| l.replace('\\', "\\\\").replace('"', "\\\""))
+                .collect::<Vec<_>>()
+                .join("\\n")
+        };
+        println!("digraph cepler {{");
+        println!("  rankdir=LR;");
+        for env in graph.nodes.iter() {
+            println!(
+                "  \"{}\" [label=\"{}\"];",
+                escape(vec![env.name.clone()]),
+                escape(label(env))
+            );
+        }
+        for edge in graph.edges.iter() {
+            println!(
+                "  \"{}\" -> \"{}\" [label=\"{}\"];",
+                escape(vec![edge.from.clone()]),
+                escape(vec![edge.to.clone()]),
+                escape(edge.files.clone())
+            );
+        }
+        println!("}}");
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn color_from_matches(matches: &ArgMatches) -> bool {
+    match matches.value_of("COLOR").unwrap() {
+        "always" => true,
+        "never" => false,
+        _ => std::io::stdout().is_terminal(),
+    }
+}
+
+fn paint(color: bool, code: &str, text: &str) -> String {
+    if color {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    } else {
+        text.to_string()
+    }
+}
+
+fn print_patch(patch: &str, color: bool) {
+    for line in patch.lines() {
+        let code = if line.starts_with("diff ")
+            || line.starts_with("index ")
+            || line.starts_with("--- ")
+            || line.starts_with("+++ ")
+        {
+            "1"
+        } else if line.starts_with("@@") {
+            "36"
+        } else if line.starts_with('+') {
+            "32"
+        } else if line.starts_with('-') {
+            "31"
+        } else {
+            ""
+        };
+        if code.is_empty() {
+            println!("{}", line);
+        } else {
+            println!("{}", paint(color, code, line));
+        }
+    }
+}
+
+fn status(ws: Workspace, output: Output) -> Result<ExitCode> {
+    let status = ws.status()?;
+    print_warnings(&ws);
+    if output == Output::Json {
+        output.json(&status)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    let mut rows = vec![[
+        "ENVIRONMENT".to_string(),
+        "VERSION".to_string(),
+        "HEAD".to_string(),
+        "PROPAGATED".to_string(),
+        "DIRTY".to_string(),
+        "QUEUE".to_string(),
+        "PENDING".to_string(),
+    ]];
+    let or_dash = |s: Option<String>| s.unwrap_or_else(|| "-".to_string());
+    let yes_no = |b: bool| if b { "yes" } else { "no" }.to_string();
+    for env in status {
+        let propagated = env
+            .propagated_heads
+            .iter()
+            .map(|(upstream, head)| format!("{}@{}", upstream, &head[..7.min(head.len())]))
+            .collect::<Vec<_>>()
+            .join(",");
+        rows.push([
+            env.name,
+            or_dash(env.version.map(|v| v.to_string())),
+            or_dash(env.head_commit.map(|h| h.chars().take(7).collect())),
+            or_dash(Some(propagated).filter(|p| !p.is_empty())),
+            if env.version.is_some() {
+                yes_no(env.any_dirty)
+            } else {
+                "-".to_string()
+            },
+            env.queue_length.to_string(),
+            match env.blocked_by {
+                Some(previous) => format!("blocked ({} not deployed)", previous),
+                None => yes_no(env.pending),
+            },
+        ]);
+    }
+    let mut widths = [0; 7];
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in rows {
+        let line = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn history(matches: &ArgMatches, ws: Workspace, output: Output) -> Result<ExitCode> {
+    let env = matches.value_of("ENVIRONMENT").unwrap();
+    let since = matches.value_of("SINCE").map(parse_date).transpose()?;
+    let limit = matches
+        .value_of("LIMIT")
+        .map(|limit| limit.parse::<usize>())
+        .transpose()
+        .context("--limit must be a number")?;
+    let history = ws.history(env, HistoryOptions { since, limit })?;
+    if output == Output::Json {
+        output.json(&history)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    if history.is_empty() {
+        eprintln!("No versions of '{}' recorded", env);
+    }
+    for entry in history {
+        let rollback = entry
+            .rollback_of
+            .map(|version| format!(" (rollback to version {})", version))
+            .unwrap_or_default();
+        println!(
+            "Version {}{} recorded at {} - trigger commit {}",
+            entry.state_id.version,
+            rollback,
+            entry.recorded_at.format("%Y-%m-%d %H:%M:%S UTC"),
+            &entry.state_id.head_commit[..7]
+        );
+        for diff in entry.diffs {
+            match diff.current_state.as_ref() {
+                Some(state) => println!("  {} {}", diff, state),
+                None => println!("  {}", diff),
+            }
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn parse_date(date: &str) -> Result<DateTime<Utc>> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(date) {
+        return Ok(date.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .with_context(|| format!("Couldn't parse date '{}'", date))?;
+    Ok(Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap()))
+}
+
+fn latest(matches: &ArgMatches, ws: Workspace, output: Output) -> Result<ExitCode> {
+    let env = matches.value_of("ENVIRONMENT").unwrap();
+    let latest = ws.latest(env)?;
+    if output == Output::Json {
+        output.json(&latest)?;
+    }
+    if let Some(state_id) = latest {
+        if output == Output::Text {
+            println!("{}", state_id.head_commit);
+        }
+        Ok(ExitCode::SUCCESS)
     } else {
         eprintln!("Environment '{}' not deployed!", env);
-        std::process::exit(1);
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+fn doctor(matches: &ArgMatches, mut ws: Workspace, output: Output) -> Result<ExitCode> {
+    let fix = matches.is_present("FIX");
+    let dangling = ws.doctor(fix)?;
+    let unresolved = dangling
+        .iter()
+        .filter(|d| !fix || d.reanchored_to.is_none())
+        .count();
+    let code = if unresolved == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    };
+    if output == Output::Json {
+        output.json(&dangling)?;
+        return Ok(code);
+    }
+    if dangling.is_empty() {
+        println!("All commits referenced by the recorded states exist");
+        return Ok(code);
+    }
+    for d in dangling.iter() {
+        let resolution = match (d.reanchored_to.as_ref(), fix) {
+            (Some(commit), true) => format!("re-anchored to {}", &commit[..7]),
+            (Some(commit), false) => format!("can be re-anchored to {}", &commit[..7]),
+            (None, _) => "no commit with identical content found".to_string(),
+        };
+        println!(
+            "{} ({}) {}: commit {} is missing - {}",
+            d.environment,
+            d.state,
+            d.reference,
+            &d.commit[..7.min(d.commit.len())],
+            resolution
+        );
+    }
+    if !fix && dangling.iter().any(|d| d.reanchored_to.is_some()) {
+        eprintln!("Run with --fix to re-anchor them");
+    }
+    Ok(code)
+}
+
+fn validate(config_file: &str, repo_path: Option<&Path>, output: Output) -> Result<ExitCode> {
+    let diagnostics = validate_config(config_file, repo_path)?;
+    if output == Output::Json {
+        output.json(&diagnostics)?;
+        return Ok(if diagnostics.is_empty() {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        });
+    }
+    if diagnostics.is_empty() {
+        println!("Config '{}' is valid", config_file);
+        return Ok(ExitCode::SUCCESS);
+    }
+    for diagnostic in diagnostics.iter() {
+        println!("{}", diagnostic);
+    }
+    eprintln!(
+        "Found {} problem(s) in '{}'",
+        diagnostics.len(),
+        config_file
+    );
+    Ok(ExitCode::FAILURE)
+}
+
+fn cache_clear(repo_path: Option<&Path>, output: Output) -> Result<ExitCode> {
+    let mut builder = Workspace::builder();
+    if let Some(path) = repo_path {
+        builder = builder.repo_path(path);
+    }
+    let cleared = builder.clear_cache()?;
+    if output == Output::Json {
+        #[derive(Serialize)]
+        struct Cleared {
+            cleared: bool,
+        }
+        output.json(&Cleared { cleared })?;
+    } else if cleared {
+        println!("Cleared the state cache");
+    } else {
+        println!("The state cache is empty");
     }
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
 
-fn concourse_check() -> Result<()> {
-    concourse::check::exec()
+fn concourse_check() -> Result<ExitCode> {
+    concourse::check::exec()?;
+    Ok(ExitCode::SUCCESS)
 }
 
-fn concourse_in(matches: &ArgMatches) -> Result<()> {
+fn concourse_in(matches: &ArgMatches) -> Result<ExitCode> {
     let destination = matches.value_of("DESTINATION").unwrap();
-    concourse::ci_in::exec(destination)
+    concourse::ci_in::exec(destination)?;
+    Ok(ExitCode::SUCCESS)
 }
 
-fn concourse_out(matches: &ArgMatches) -> Result<()> {
+fn concourse_out(matches: &ArgMatches) -> Result<ExitCode> {
     let origin = matches.value_of("ORIGIN").unwrap();
-    concourse::ci_out::exec(origin)
+    concourse::ci_out::exec(origin)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn credentials_from_matches(matches: &ArgMatches) -> Result<GitCredentials> {
+    let value = |name| matches.value_of(name).map(|v| v.to_string());
+    let host_key_check = if matches.is_present("INSECURE_SKIP_HOST_KEY_CHECK") {
+        HostKeyCheck::Insecure
+    } else if let Some(path) = matches.value_of("KNOWN_HOSTS") {
+        HostKeyCheck::KnownHosts(
+            std::fs::read_to_string(path)
+                .with_context(|| format!("Couldn't read known_hosts file '{}'", path))?,
+        )
+    } else {
+        HostKeyCheck::UserKnownHosts
+    };
+    Ok(GitCredentials {
+        host_key_check,
+        private_key: value("GIT_PRIVATE_KEY"),
+        private_key_path: value("GIT_PRIVATE_KEY_PATH").map(PathBuf::from),
+        passphrase: value("GIT_PASSPHRASE"),
+        username: value("GIT_USERNAME"),
+        password: value("GIT_PASSWORD").or_else(|| value("GIT_TOKEN")),
+    })
+}
+
+fn clone_config(matches: &ArgMatches) -> Result<Option<GitConfig>> {
+    let dir = match matches.value_of("CLONE_DIR") {
+        Some(dir) => dir,
+        None => return Ok(None),
+    };
+    Ok(Some(GitConfig {
+        url: matches.value_of("GIT_URL").unwrap().to_string(),
+        branch: matches.value_of("GIT_BRANCH").unwrap().to_string(),
+        gates_branch: matches.value_of("GATES_BRANCH").map(|b| b.to_string()),
+        credentials: credentials_from_matches(matches)?,
+        dir: dir.to_string(),
+        depth: matches
+            .value_of("GIT_DEPTH")
+            .map(|depth| {
+                depth
+                    .parse()
+                    .with_context(|| format!("Depth '{}' is not a number", depth))
+            })
+            .transpose()?,
+    }))
 }
 
-fn conf_from_matches(matches: &ArgMatches) -> Result<(Config, String)> {
-    let file_name = matches.value_of("CONFIG_FILE").unwrap();
-    Ok((Config::from_file(file_name)?, file_name.to_string()))
+fn workspace_from_matches(matches: &ArgMatches, repo_path: Option<&Path>) -> Result<Workspace> {
+    let repo = Repo::open(repo_path, None)?;
+    let mut builder = Workspace::builder()
+        .repo_path(repo.workdir())
+        .config_path(matches.value_of("CONFIG_FILE").unwrap())
+        .ignore_queue(matches.is_present("IGNORE_QUEUE"))
+        .no_cache(matches.is_present("NO_CACHE"));
+    if let Some(gates) = gates_from_matches(matches, &repo)? {
+        builder = builder.gates(gates);
+    }
+    if let Some(store) = matches.value_of("STATE_STORE") {
+        builder = builder.state_store(store.parse()?);
+    }
+    if let Some(state_ref) = matches.value_of("STATE_REF") {
+        builder = builder.state_ref(state_ref);
+    }
+    let mut ws = builder.build()?;
+    if let Some(conf) = clone_config(matches)? {
+        ws.fetch_state(&conf)?;
+    }
+    Ok(ws)
 }
 
-#[allow(clippy::redundant_closure)]
-fn gates_from_matches(matches: &ArgMatches) -> Result<Option<GatesConfig>> {
+fn gates_from_matches(matches: &ArgMatches, repo: &Repo) -> Result<Option<GatesConfig>> {
     let file_name = matches.value_of("GATES_FILE");
     if let Some(branch) = matches.value_of("GATES_BRANCH") {
-        match Repo::open(None)?.get_file_from_branch(
-            branch,
-            Path::new(file_name.unwrap()),
-            |bytes| GatesConfig::from_reader(bytes),
-        ) {
+        match repo.get_file_from_branch(branch, Path::new(file_name.unwrap()), |bytes| {
+            Ok(GatesConfig::from_reader(bytes)?)
+        }) {
             Ok(Some(config)) => Ok(Some(config)),
             Ok(_) => Err(anyhow!("Couldn't find gates file in branch")),
-            err => err,
+            Err(e) => Err(e),
         }
     } else if let Some(f) = file_name {
-        Ok(Some(GatesConfig::from_file(f)?))
+        Ok(Some(GatesConfig::from_file(repo.workdir().join(f))?))
     } else {
         Ok(None)
     }
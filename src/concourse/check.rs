@@ -1,5 +1,5 @@
 use super::*;
-use crate::{config::Config, workspace::Workspace};
+use crate::workspace::CheckResult;
 use std::{
     env,
     fs::File,
@@ -37,23 +37,21 @@ pub fn exec() -> Result<()> {
     ))?;
     file.write_all(&serde_json::to_vec(&resource)?)?;
     let conf = GitConfig {
-        url: source.uri,
+        url: source.uri.clone(),
         branch: source.branch.clone(),
         gates_branch: source.gates_branch.clone(),
-        private_key: source.private_key,
+        credentials: source.credentials(),
         dir: clone_dir.clone(),
+        depth: source.depth,
     };
     let path = path::Path::new(&clone_dir);
     let repo = if !path.exists() || path.read_dir()?.next().is_none() {
         eprintln!("Cloning repo");
-        let repo = Repo::clone(conf).context("Couldn't clone repo")?;
-        std::env::set_current_dir(path)?;
-        repo
+        Repo::clone(conf.clone()).context("Couldn't clone repo")?
     } else {
         eprintln!("Pulling latest state");
-        std::env::set_current_dir(path)?;
-        let repo = Repo::open(None)?;
-        repo.pull(conf)?;
+        let repo = Repo::open(Some(path), None)?;
+        repo.pull(conf.clone())?;
         repo
     };
     let (hash, summary) = repo.head_commit_summary()?;
@@ -62,8 +60,8 @@ pub fn exec() -> Result<()> {
         source.branch, hash, summary
     );
 
-    let config = Config::from_file(&source.config)?;
-    let ws = Workspace::new(&config.scope, source.config.clone(), source.ignore_queue)?;
+    let mut ws = workspace(&source, &repo)?;
+    ws.fetch_state(&conf)?;
     let mut res = Vec::new();
     let environment = if let Some(environment) = source.environment {
         environment
@@ -72,30 +70,31 @@ pub fn exec() -> Result<()> {
         println!("{}", serde_json::to_string(&res)?);
         return Ok(());
     };
-    let env = config.environments.get(&environment).context(format!(
-        "Environment '{}' not found in config '{}'",
-        environment, source.config
-    ))?;
     eprintln!("Checking equivalence with last deployed state...");
-    let gate = get_gate(
-        source.gates_file.as_ref(),
-        source.gates_branch.as_ref(),
-        &environment,
-        &repo,
-    )?;
-    match (version, ws.check(env, gate)?) {
-        (None, Some((state_id, _))) => {
+    let check = ws.check(&environment)?;
+    for warning in ws.take_warnings() {
+        eprintln!("WARNING {}", warning);
+    }
+    if let Some(CheckResult { diffs, .. }) = check.as_ref() {
+        for diff in diffs.iter() {
+            eprintln!("{}", diff);
+        }
+    }
+    match (version, check) {
+        (None, Some(CheckResult { state_id, .. })) => {
             eprintln!("Found new state to deploy");
             res.push(Version::from(state_id))
         }
-        (Some(last), Some((state_id, _))) if last.trigger != state_id.head_commit => {
+        (Some(last), Some(CheckResult { state_id, .. }))
+            if last.trigger != state_id.head_commit =>
+        {
             eprintln!("Found new state to deploy");
             res.push(last);
             res.push(Version::from(state_id))
         }
         (Some(last), ret) => {
             match ret {
-                Some((state_id, _)) if last.trigger == state_id.head_commit => {
+                Some(CheckResult { state_id, .. }) if last.trigger == state_id.head_commit => {
                     eprintln!("Last trigger is still up to date")
                 }
                 _ => eprintln!("Nothing new to deploy"),
@@ -1,5 +1,5 @@
 use super::*;
-use crate::workspace::Workspace;
+use crate::workspace::CheckResult;
 use glob::*;
 use std::{io, path::Path};
 
@@ -15,80 +15,69 @@ pub fn exec(destination: &str) -> Result<()> {
     eprintln!("Cloning repo to '{}'", destination);
     let version = version.expect("No version specified");
     let conf = GitConfig {
-        url: source.uri,
+        url: source.uri.clone(),
         branch: source.branch.clone(),
         gates_branch: source.gates_branch.clone(),
-        private_key: source.private_key,
+        credentials: source.credentials(),
         dir: destination.to_string(),
+        depth: source.depth,
     };
 
     let path = Path::new(&destination);
-    let repo = Repo::clone(conf).context("Couldn't clone repo")?;
-    std::env::set_current_dir(path)?;
+    let repo = Repo::clone(conf.clone()).context("Couldn't clone repo")?;
     let (hash, summary) = repo.head_commit_summary()?;
     eprintln!(
         "HEAD of branch '{}' is now at: [{}] - {}",
         source.branch, hash, summary
     );
 
-    let config = Config::from_file(&source.config)?;
-    let ws = Workspace::new(&config.scope, source.config, source.ignore_queue)?;
+    let mut ws = workspace(&source, &repo)?;
+    ws.fetch_state(&conf)?;
     let environment = if let Some(environment) = source.environment {
         environment
     } else {
         eprintln!("No environment specified... providing an empty dir");
-        return empty_repo(version);
+        return empty_repo(path, version);
     };
-    let env = config
-        .environments
-        .get(&environment)
-        .context(format!("Environment '{}' not found in config", environment))?;
     eprintln!(
         "Checking if we can prepare deployment at trigger '{}'",
         version.trigger
     );
     let wanted_trigger = &version.trigger;
-    let gate = get_gate(
-        source.gates_file.as_ref(),
-        source.gates_branch.as_ref(),
-        &environment,
-        &repo,
-    )?;
 
     let (state_id, diff) = if should_prepare {
-        match ws.check(env, gate.clone())? {
-            Some((state_id, _)) if &state_id.head_commit != wanted_trigger => {
-                eprintln!("Trigger is out of sync.");
-                std::process::exit(1);
+        match ws.check(&environment)? {
+            Some(CheckResult { state_id, .. }) if &state_id.head_commit != wanted_trigger => {
+                return Err(anyhow!("Trigger is out of sync."));
             }
             None => {
                 eprintln!("Nothing new to deploy... reproducing last state");
-                let state_id = ws.reproduce(env, true)?;
+                let state_id = ws.reproduce(&environment, true)?.state_id;
                 if &state_id.head_commit != wanted_trigger {
                     eprintln!("Reproduced state is out of sync - providing empty dir");
-                    return empty_repo(version);
+                    return empty_repo(path, version);
                 }
                 (state_id, Vec::new())
             }
-            Some(ret) => {
+            Some(CheckResult { state_id, diffs }) => {
                 eprintln!("Preparing the workspace");
-                ws.prepare(env, gate, true)?;
-                ret
+                ws.prepare(&environment, true)?;
+                (state_id, diffs)
             }
         }
     } else {
         eprintln!("Reproducing last state");
-        let state_id = ws.reproduce(env, true)?;
+        let state_id = ws.reproduce(&environment, true)?.state_id;
         if &state_id.head_commit != wanted_trigger {
             eprintln!("Reproduced state is out of sync - providing empty dir");
-            return empty_repo(version);
+            return empty_repo(path, version);
         }
         (state_id, Vec::new())
     };
 
-    std::fs::write(".git/cepler_environment", &environment)
+    std::fs::write(path.join(".git/cepler_environment"), &environment)
         .context("Couldn't create file '.git/cepler_environment'")?;
-    std::fs::write(".git/cepler_trigger", state_id.head_commit)
+    std::fs::write(path.join(".git/cepler_trigger"), state_id.head_commit)
         .context("Couldn't create file '.git/cepler_trigger'")?;
 
     println!(
@@ -110,8 +99,9 @@ pub fn exec(destination: &str) -> Result<()> {
     Ok(())
 }
 
-fn empty_repo(version: Version) -> Result<()> {
-    for path in glob("*")? {
+fn empty_repo(destination: &Path, version: Version) -> Result<()> {
+    let pattern = format!("{}/*", Pattern::escape(&destination.to_string_lossy()));
+    for path in glob(&pattern)? {
         let path = path?;
         if path.is_dir() {
             std::fs::remove_dir_all(path).context("Couldn't remove dir")?;
@@ -1,5 +1,5 @@
 use super::*;
-use crate::{config::Config, workspace::Workspace};
+use crate::workspace::{RecordOptions, DEFAULT_PUSH_RETRIES};
 use std::{io, path};
 
 pub fn exec(origin: &str) -> Result<()> {
@@ -7,41 +7,45 @@ pub fn exec(origin: &str) -> Result<()> {
     let ResourceConfig { source, params, .. }: ResourceConfig<OutParams> =
         serde_json::from_reader(io::stdin()).context("Deserializing stdin")?;
     let out_params = params.unwrap();
-    std::env::set_current_dir(path::Path::new(&format!(
-        "{}/{}",
-        origin, out_params.repository
-    )))?;
+    let repo_path = path::Path::new(origin).join(&out_params.repository);
 
     let conf = GitConfig {
-        url: source.uri,
+        url: source.uri.clone(),
         branch: source.branch.clone(),
         gates_branch: source.gates_branch.clone(),
-        private_key: source.private_key,
+        credentials: source.credentials(),
         dir: origin.to_string(),
+        depth: source.depth,
     };
-    let config = Config::from_file(&source.config)?;
     let environment = out_params.environment.ok_or(()).or_else(|_| {
         source
             .environment
+            .clone()
             .ok_or_else(|| anyhow!("Environment not specified in source"))
     })?;
-    let mut ws = Workspace::new(&config.scope, source.config, source.ignore_queue)?;
-    let env = config
-        .environments
-        .get(&environment)
-        .context(format!("Environment '{}' not found in config", environment))?;
-    let gate = get_gate(
-        source.gates_file.as_ref(),
-        source.gates_branch.as_ref(),
+    let mut ws = workspace(&source, &Repo::open(Some(&repo_path), None)?)?;
+    if let Some(version) = out_params.rollback_to {
+        eprintln!("Rolling back '{}' to version {}", environment, version);
+        ws.rollback(&environment, version, false)?;
+    }
+    let result = ws.record(
         &environment,
-        &Repo::open(None)?,
+        RecordOptions {
+            commit: true,
+            reset_head: true,
+            push: Some(conf),
+            push_retries: out_params.push_retries.unwrap_or(DEFAULT_PUSH_RETRIES),
+        },
     )?;
-    let (state_id, diff) = ws.record_env(env, gate, true, true, Some(conf))?;
+    for warning in ws.take_warnings() {
+        eprintln!("WARNING {}", warning);
+    }
     println!(
         "{}",
         serde_json::to_string(&ResourceData {
-            version: Version::from(state_id),
-            metadata: diff
+            version: Version::from(result.state_id),
+            metadata: result
+                .diffs
                 .into_iter()
                 .map(|diff| DiffElem {
                     name: diff.ident.inner(),
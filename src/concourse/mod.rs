@@ -1,4 +1,8 @@
-use crate::{config::*, repo::*, workspace::StateId};
+use crate::{
+    config::*,
+    repo::*,
+    workspace::{StateId, Workspace},
+};
 use anyhow::*;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::path::Path;
@@ -20,13 +24,37 @@ struct Source {
     branch: String,
     gates_branch: Option<String>,
     gates_file: Option<String>,
-    private_key: String,
+    private_key: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    known_hosts: Option<String>,
+    #[serde(default = "bool::default")]
+    insecure_skip_host_key_check: bool,
     environment: Option<String>,
     #[serde(default = "bool::default")]
     ignore_queue: bool,
     #[serde(default = "default_config_path")]
     config: String,
+    /// Only clone this many commits of history.
+    depth: Option<u32>,
+}
+impl Source {
+    fn credentials(&self) -> GitCredentials {
+        let host_key_check = match &self.known_hosts {
+            _ if self.insecure_skip_host_key_check => HostKeyCheck::Insecure,
+            Some(known_hosts) => HostKeyCheck::KnownHosts(known_hosts.clone()),
+            None => HostKeyCheck::UserKnownHosts,
+        };
+        GitCredentials {
+            host_key_check,
+            private_key: self.private_key.clone(),
+            username: self.username.clone(),
+            password: self.password.clone(),
+            ..GitCredentials::default()
+        }
+    }
 }
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 struct Version {
     trigger: String,
@@ -45,6 +73,9 @@ impl From<StateId> for Version {
 struct OutParams {
     repository: String,
     environment: Option<String>,
+    push_retries: Option<u32>,
+    /// Record this earlier version again instead of the prepared state.
+    rollback_to: Option<u32>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -73,17 +104,12 @@ fn default_config_path() -> String {
     "cepler.yml".to_string()
 }
 
-fn get_gate(
-    gates_file: Option<&String>,
-    gates_branch: Option<&String>,
-    env: &str,
-    repo: &Repo,
-) -> Result<Option<String>> {
-    let gates = match (gates_file, gates_branch) {
+fn workspace(source: &Source, repo: &Repo) -> Result<Workspace> {
+    let gates = match (source.gates_file.as_ref(), source.gates_branch.as_ref()) {
         (Some(gates_file), Some(gates_branch)) => {
             if let Some(file) =
                 repo.get_file_from_branch(gates_branch, Path::new(&gates_file), |bytes| {
-                    GatesConfig::from_reader(bytes)
+                    Ok(GatesConfig::from_reader(bytes)?)
                 })?
             {
                 Ok(Some(file))
@@ -91,16 +117,20 @@ fn get_gate(
                 Err(anyhow!("Couldn't read gates file"))
             }
         }
-        (Some(gates_file), _) => Ok(Some(GatesConfig::from_file(gates_file)?)),
+        (Some(gates_file), _) => Ok(Some(GatesConfig::from_file(
+            repo.workdir().join(gates_file),
+        )?)),
 
         (_, Some(_)) => Err(anyhow!("Missing gates_file in source")),
         _ => Ok(None),
     }?;
 
-    let gate = if let Some(gates) = gates {
-        gates.get_gate(env)?
-    } else {
-        None
-    };
-    Ok(gate)
+    let mut builder = Workspace::builder()
+        .repo_path(repo.workdir())
+        .config_path(source.config.clone())
+        .ignore_queue(source.ignore_queue);
+    if let Some(gates) = gates {
+        builder = builder.gates(gates);
+    }
+    Ok(builder.build()?)
 }
@@ -1,7 +1,8 @@
-use anyhow::*;
+use super::error::*;
 use serde::Deserialize;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
     fs::File,
     io::{BufReader, Read},
     path::Path,
@@ -18,61 +19,194 @@ pub struct Config {
     #[serde(default = "default_scope")]
     #[serde(rename = "deployment")]
     pub scope: String,
+    /// Where the state of the environments is kept.
+    #[serde(default)]
+    pub state_store: StateStoreKind,
+    /// Branch of the `branch` store or notes ref of the `notes` store.
+    #[serde(default)]
+    pub state_ref: Option<String>,
+    /// Which parents to follow when walking back through the history.
+    #[serde(default)]
+    pub history: HistoryMode,
+    /// Default of `max_history_depth` for all environments.
+    #[serde(default)]
+    pub max_history_depth: Option<u32>,
+    /// Default of `max_history_age` for all environments.
+    #[serde(default)]
+    pub max_history_age: Option<String>,
     pub environments: HashMap<String, EnvironmentConfig>,
 }
 
 impl Config {
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let file = File::open(path).context("Couldn't open config file")?;
+        let file = File::open(path).map_err(|source| CeplerError::Io {
+            message: "Couldn't open config file".to_string(),
+            source,
+        })?;
         let reader = BufReader::new(file);
 
         Self::from_reader(reader)
     }
 
     pub fn from_reader(reader: impl Read) -> Result<Self> {
-        let mut config: Config = serde_yaml::from_reader(reader)?;
-        let all_environments: HashSet<String> = config.environments.keys().cloned().collect();
-        for (name, env) in config.environments.iter_mut() {
-            env.name = name.clone();
-            if let Some(previous) = env.propagated_from.as_ref() {
+        let config = Self::from_reader_unchecked(reader)?;
+        let all_environments: HashSet<&String> = config.environments.keys().collect();
+        if let Some(age) = config.max_history_age.as_deref() {
+            parse_age(age).ok_or_else(|| {
+                CeplerError::InvalidConfig(format!("Malformed max_history_age '{}'", age))
+            })?;
+        }
+        for (name, env) in config.environments.iter() {
+            if let Some(age) = env.max_history_age.as_deref() {
+                parse_age(age).ok_or_else(|| {
+                    CeplerError::InvalidConfig(format!(
+                        "Environment '{}' has malformed max_history_age '{}'",
+                        name, age
+                    ))
+                })?;
+            }
+            for pattern in env.head_files().iter().chain(env.propagated_files()) {
+                glob::Pattern::new(pattern).map_err(|e| {
+                    CeplerError::InvalidConfig(format!(
+                        "Environment '{}' has malformed glob pattern '{}': {}",
+                        name, pattern, e
+                    ))
+                })?;
+            }
+            for previous in env.propagated_from.iter() {
                 if !all_environments.contains(previous) {
-                    return Err(anyhow!("Previous environment '{}' not defined", previous));
+                    return Err(CeplerError::InvalidConfig(format!(
+                        "Previous environment '{}' not defined",
+                        previous
+                    )));
+                }
+                if previous == name {
+                    return Err(CeplerError::InvalidConfig(format!(
+                        "Environment '{}' cannot pass itself",
+                        name
+                    )));
+                }
+            }
+            if let PropagatedFiles::PerUpstream(files) = &env.propagated_files {
+                if let Some(upstream) = files.keys().find(|u| !env.propagated_from.contains(u)) {
+                    return Err(CeplerError::InvalidConfig(format!(
+                        "Environment '{}' propagates files from '{}' which is not listed in 'passed'",
+                        name, upstream
+                    )));
                 }
             }
         }
 
         Ok(config)
     }
+
+    /// Deserializes the config without checking that it is consistent.
+    /// Used by `validate` to report every problem instead of the first one.
+    pub fn from_reader_unchecked(reader: impl Read) -> Result<Self> {
+        let mut config: Config =
+            serde_yaml::from_reader(reader).map_err(|source| CeplerError::Yaml {
+                message: "Couldn't parse config".to_string(),
+                source,
+            })?;
+        for (name, env) in config.environments.iter_mut() {
+            env.name = name.clone();
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StateStoreKind {
+    /// `.state` files committed next to the config file.
+    #[default]
+    Tree,
+    /// `.state` files committed to a separate orphan branch.
+    Branch,
+    /// git notes attached to the recorded commits.
+    Notes,
 }
 
-#[derive(Debug)]
+impl std::str::FromStr for StateStoreKind {
+    type Err = CeplerError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tree" => Ok(StateStoreKind::Tree),
+            "branch" => Ok(StateStoreKind::Branch),
+            "notes" => Ok(StateStoreKind::Notes),
+            _ => Err(CeplerError::InvalidConfig(format!(
+                "Unknown state store '{}'",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for StateStoreKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateStoreKind::Tree => write!(f, "tree"),
+            StateStoreKind::Branch => write!(f, "branch"),
+            StateStoreKind::Notes => write!(f, "notes"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HistoryMode {
+    /// Follow every parent of merge commits.
+    #[default]
+    All,
+    /// Only follow the first parent so triggers and `from_commit`s stay on the mainline.
+    FirstParent,
+}
+
+impl fmt::Display for HistoryMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryMode::All => write!(f, "all"),
+            HistoryMode::FirstParent => write!(f, "first-parent"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct GatesConfig {
     gates: HashMap<String, String>,
 }
 
 impl GatesConfig {
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let file = File::open(path).context("Couldn't open gates file")?;
+        let file = File::open(path).map_err(|source| CeplerError::Io {
+            message: "Couldn't open gates file".to_string(),
+            source,
+        })?;
         let reader = BufReader::new(file);
 
         Self::from_reader(reader)
     }
 
     pub fn from_reader(reader: impl Read) -> Result<Self> {
-        let gates: HashMap<String, String> = serde_yaml::from_reader(reader)?;
+        let gates: HashMap<String, String> =
+            serde_yaml::from_reader(reader).map_err(|source| CeplerError::Yaml {
+                message: "Couldn't parse gates file".to_string(),
+                source,
+            })?;
 
         Ok(GatesConfig { gates })
     }
 
-    pub fn get_gate(mut self, env: &str) -> Result<Option<String>> {
+    pub fn get_gate(&self, env: &str) -> Result<Option<String>> {
         let gate = self
             .gates
-            .remove(env)
-            .context("Environment is missing in gates file")?;
+            .get(env)
+            .ok_or_else(|| CeplerError::GateNotFound(env.to_string()))?;
         if gate == "HEAD" {
             Ok(None)
         } else {
-            Ok(Some(gate))
+            Ok(Some(gate.clone()))
         }
     }
 }
@@ -84,35 +218,126 @@ pub struct EnvironmentConfig {
     #[serde(default)]
     pub ignore_queue: bool,
     #[serde(rename = "passed")]
-    propagated_from: Option<String>,
+    #[serde(default, with = "one_or_many")]
+    propagated_from: Vec<String>,
     #[serde(rename = "propagated")]
     #[serde(default)]
-    propagated_files: Vec<String>,
+    propagated_files: PropagatedFiles,
     #[serde(rename = "latest")]
     #[serde(default)]
     head_files: Vec<String>,
+    /// How many commits to look back for the commit that triggered the current state.
+    #[serde(default)]
+    pub max_history_depth: Option<u32>,
+    /// How far to look back for the commit that triggered the current state,
+    /// eg. `12h`, `30d` or `2w`.
+    #[serde(default)]
+    pub max_history_age: Option<String>,
 }
 
 impl EnvironmentConfig {
-    pub fn propagated_from(&self) -> Option<&String> {
-        self.propagated_from.as_ref()
+    pub fn propagated_from(&self) -> &[String] {
+        &self.propagated_from
     }
 
-    pub fn propagated_file_patterns(&self) -> impl Iterator<Item = glob::Pattern> + '_ {
-        self.propagated_files
+    pub fn propagated_file_patterns(
+        &self,
+        upstream: &str,
+    ) -> impl Iterator<Item = glob::Pattern> + '_ {
+        self.propagated_files_from(upstream)
             .iter()
-            .cloned()
-            .map(|path| glob::Pattern::new(&path).expect("Couldn't compile glob pattern"))
+            .map(|path| glob::Pattern::new(path).expect("Couldn't compile glob pattern"))
+    }
+
+    pub fn propagated_files_from(&self, upstream: &str) -> &[String] {
+        match &self.propagated_files {
+            PropagatedFiles::Shared(files) => files.as_slice(),
+            PropagatedFiles::PerUpstream(files) => {
+                files.get(upstream).map(Vec::as_slice).unwrap_or_default()
+            }
+        }
+    }
+
+    /// All `propagated` globs regardless of the upstream they apply to.
+    pub fn propagated_files(&self) -> Vec<&String> {
+        match &self.propagated_files {
+            PropagatedFiles::Shared(files) => files.iter().collect(),
+            PropagatedFiles::PerUpstream(files) => files.values().flatten().collect(),
+        }
+    }
+
+    pub fn head_files(&self) -> &[String] {
+        &self.head_files
     }
 
     pub fn head_file_patterns(&self) -> impl Iterator<Item = glob::Pattern> + '_ {
         self.head_files
             .iter()
-            .cloned()
-            .map(|path| glob::Pattern::new(&path).expect("Couldn't compile glob pattern"))
+            .map(|path| glob::Pattern::new(path).expect("Couldn't compile glob pattern"))
+    }
+}
+
+/// The `propagated` files of an environment. Either one list shared by all
+/// upstream environments or a list per environment named in `passed`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum PropagatedFiles {
+    Shared(Vec<String>),
+    PerUpstream(BTreeMap<String, Vec<String>>),
+}
+
+impl Default for PropagatedFiles {
+    fn default() -> Self {
+        PropagatedFiles::Shared(Vec::new())
     }
 }
 
+/// (De)serializes a list of environment names that may also be written as a
+/// single string.
+pub mod one_or_many {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    pub fn serialize<S: Serializer>(names: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+        match names {
+            [name] => name.serialize(serializer),
+            names => names.serialize(serializer),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<String>, D::Error> {
+        Ok(match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(name) => vec![name],
+            OneOrMany::Many(names) => names,
+        })
+    }
+}
+
+/// Parses an age like `90m`, `12h`, `30d` or `2w` into seconds.
+pub fn parse_age(age: &str) -> Option<i64> {
+    let unit = match age.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    let amount: i64 = age[..age.len() - 1].parse().ok()?;
+    if amount < 0 {
+        return None;
+    }
+    Some(amount * unit)
+}
+
 pub fn default_scope() -> String {
     "default".to_string()
 }
@@ -141,6 +366,69 @@ mod test {
         assert!(
             conf.environments.get("testflight").unwrap().head_files == vec!["file.yml".to_string()]
         );
-        assert!(conf.scope == "default".to_string());
+        assert!(conf.scope == "default");
+        assert!(conf.state_store == StateStoreKind::Tree);
+    }
+
+    #[test]
+    fn deserialize_state_store() {
+        let conf = r#"state_store: notes
+state_ref: deployments
+environments:
+  testflight:
+    latest:
+    - file.yml"#;
+
+        let conf = Config::from_reader(StringReader::new(conf)).unwrap();
+        assert!(conf.state_store == StateStoreKind::Notes);
+        assert!(conf.state_ref.as_deref() == Some("deployments"));
+    }
+
+    #[test]
+    fn deserialize_fan_in_config() {
+        let conf = r#"environments:
+  staging-eu:
+    latest:
+    - eu.yml
+  staging-us:
+    latest:
+    - us.yml
+  production:
+    passed: [staging-eu, staging-us]
+    propagated:
+      staging-eu:
+      - eu.yml
+      staging-us:
+      - us.yml"#;
+
+        let conf = Config::from_reader(StringReader::new(conf)).unwrap();
+        let production = conf.environments.get("production").unwrap();
+        assert!(production.propagated_from() == ["staging-eu", "staging-us"]);
+        let patterns: Vec<_> = production.propagated_file_patterns("staging-us").collect();
+        assert!(patterns == vec![glob::Pattern::new("us.yml").unwrap()]);
+    }
+
+    #[test]
+    fn reject_propagated_from_unknown_upstream() {
+        let conf = r#"environments:
+  staging:
+    latest:
+    - file.yml
+  production:
+    passed: staging
+    propagated:
+      testflight:
+      - file.yml"#;
+
+        assert!(Config::from_reader(StringReader::new(conf)).is_err());
+    }
+
+    #[test]
+    fn parse_history_age() {
+        assert_eq!(parse_age("90m"), Some(90 * 60));
+        assert_eq!(parse_age("2w"), Some(14 * 24 * 60 * 60));
+        assert_eq!(parse_age("30"), None);
+        assert_eq!(parse_age("d"), None);
+        assert_eq!(parse_age("-1d"), None);
     }
 }
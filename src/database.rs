@@ -1,18 +1,25 @@
-use super::{config::*, repo::*};
+use super::{
+    config::*,
+    error::CeplerError,
+    repo::*,
+    state_store::{RecordedState, StateLock, StateStore},
+};
 use anyhow::*;
-use glob::*;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
 use std::{
-    collections::{BTreeMap, HashSet, VecDeque},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     fmt,
-    fs::File,
-    io::{BufReader, Read},
+    io::Read,
     path::Path,
+    rc::Rc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 pub struct Database {
     state: DbState,
     ignore_queue: bool,
+    store: Rc<dyn StateStore>,
+    /// Relative to the root of the repository.
     pub state_dir: String,
 }
 
@@ -32,30 +39,31 @@ impl Database {
         )
     }
 
-    pub fn open(scope: &str, path_to_config: &str, ignore_queue: bool) -> Result<Self> {
-        let mut state = DbState::default();
+    pub fn open(
+        repo: &Repo,
+        store: Rc<dyn StateStore>,
+        scope: &str,
+        path_to_config: &str,
+        ignore_queue: bool,
+    ) -> Result<Self> {
         let dir = Self::state_dir_from_config(scope, path_to_config);
-        if Path::new(&dir).is_dir() {
-            for path in glob(&format!("{}/*.state", dir))? {
-                let path = path?;
-                if let Some(name) = path.as_path().file_stem() {
-                    let file = File::open(&path)?;
-                    let reader = BufReader::new(file);
-                    state.environments.insert(
-                        name.to_str().expect("Convert name").to_string(),
-                        EnvironmentState::from_reader(reader)?,
-                    );
-                }
-            }
-        }
+        let state = DbState {
+            environments: store.load(repo, &dir)?.into_iter().collect(),
+        };
 
         Ok(Self {
             state,
+            store,
             state_dir: dir,
             ignore_queue,
         })
     }
 
+    fn reload(&mut self, repo: &Repo) -> Result<()> {
+        self.state.environments = self.store.load(repo, &self.state_dir)?.into_iter().collect();
+        Ok(())
+    }
+
     pub fn open_env_from_commit(
         &self,
         path_to_config: &str,
@@ -72,17 +80,14 @@ impl Database {
                 .environments
                 .insert(env_config.name.to_string(), env_state.clone());
         }
-        if let Some(last_env) = env_config.propagated_from() {
-            let env_file = format!("{}/{}.state", dir, last_env);
-            let env_path = Path::new(&env_file);
-            if let Some(env_state) = repo.get_file_content(commit, env_path, |bytes| {
-                EnvironmentState::from_reader(bytes)
-            })? {
+        for last_env in env_config.propagated_from() {
+            if let Some(env_state) = self.store.load_at_commit(repo, &dir, last_env, &commit)? {
                 state.environments.insert(last_env.to_string(), env_state);
             }
         }
         Ok(Self {
             state,
+            store: Rc::clone(&self.store),
             state_dir: dir,
             ignore_queue,
         })
@@ -90,16 +95,27 @@ impl Database {
 
     pub fn set_current_environment_state(
         &mut self,
+        repo: &Repo,
+        scope: &str,
         name: String,
-        propagated_from: Option<String>,
+        propagated_from: Vec<String>,
         mut env: DeployState,
+        commit: bool,
     ) -> Result<(u32, String)> {
+        let _lock = StateLock::acquire(repo, &self.state_dir)?;
+        // Another process may have recorded since the state was loaded.
+        self.reload(repo)?;
         let any_dirty = env.files.values().any(|f| f.dirty);
         env.any_dirty = any_dirty;
-        let ret = format!("{}/{}.state", self.state_dir, &name);
         let version = if let Some(state) = self.state.environments.get_mut(&name) {
             std::mem::swap(&mut state.current, &mut env);
-            state.propagation_queue.push_front(env);
+            if state.current.rollback_of.is_some() {
+                // States recorded after the version that was rolled back to
+                // must not be propagated anymore.
+                state.propagation_queue.clear();
+            } else {
+                state.propagation_queue.push_front(env);
+            }
             state.propagated_from = propagated_from;
             state.version += 1;
             state.version
@@ -116,38 +132,332 @@ impl Database {
             );
             version
         };
-        self.state.prune_propagation_queue(name);
-        self.persist()?;
-        Ok((version, ret))
+        self.state.prune_propagation_queue(name.clone());
+        let location = self.store.persist(
+            repo,
+            &self.state_dir,
+            scope,
+            &name,
+            &self.state.environments[&name],
+            commit,
+        )?;
+        Ok((version, location))
     }
 
-    pub fn get_target_propagated_state(
+    /// Publishes the last recorded state of `env` to the remote.
+    /// Retries up to `retries` times with exponential backoff when the remote
+    /// moved on while pushing.
+    pub fn push(
+        &self,
+        repo: &Repo,
+        config: GitConfig,
+        scope: &str,
+        env: &str,
+        retries: u32,
+    ) -> Result<bool> {
+        let state = self
+            .state
+            .environments
+            .get(env)
+            .context("Environment not recorded")?;
+        let mut attempt = 0;
+        loop {
+            match self
+                .store
+                .push(repo, config.clone(), &self.state_dir, scope, env, state)
+            {
+                Err(e)
+                    if attempt < retries
+                        && matches!(
+                            e.downcast_ref::<CeplerError>(),
+                            Some(CeplerError::PushRejected(_))
+                        ) =>
+                {
+                    std::thread::sleep(push_backoff(attempt));
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Updates the state from the remote and reloads it.
+    pub fn fetch(&mut self, repo: &Repo, config: &GitConfig) -> Result<()> {
+        self.store.fetch(repo, config)?;
+        self.state = DbState {
+            environments: self
+                .store
+                .load(repo, &self.state_dir)?
+                .into_iter()
+                .collect(),
+        };
+        Ok(())
+    }
+
+    /// Finds the commits referenced by the recorded states that don't exist in `repo`,
+    /// eg. after the history was rewritten by a force push. Files are re-anchored to the
+    /// commit that introduced their recorded content, heads follow their files or the
+    /// re-anchored head of the upstream environment. With `fix` the re-anchored states
+    /// are persisted.
+    pub fn repair(&mut self, repo: &Repo, scope: &str, fix: bool) -> Result<Vec<DanglingCommit>> {
+        let _lock = if fix {
+            let lock = StateLock::acquire(repo, &self.state_dir)?;
+            self.reload(repo)?;
+            Some(lock)
+        } else {
+            None
+        };
+        let mut names: Vec<String> = self.state.environments.keys().cloned().collect();
+        names.sort();
+        let mut dangling = Vec::new();
+        let mut reanchored: HashMap<CommitHash, CommitHash> = HashMap::new();
+        let mut changed = HashSet::new();
+        let mut dangling_heads = Vec::new();
+        for name in names.iter() {
+            let env_state = self.state.environments.get_mut(name).unwrap();
+            let states = std::iter::once(&mut env_state.current)
+                .chain(env_state.propagation_queue.iter_mut())
+                .enumerate();
+            for (idx, state) in states {
+                let label = match idx {
+                    0 => "current".to_string(),
+                    idx => format!("queue[{}]", idx - 1),
+                };
+                for (ident, file) in state.files.iter_mut() {
+                    if repo.has_commit(&file.from_commit) {
+                        continue;
+                    }
+                    let anchor = match file.file_hash.as_ref() {
+                        Some(hash) => repo.find_commit_with_blob(Path::new(&ident.name()), hash)?,
+                        None => None,
+                    };
+                    dangling.push(DanglingCommit {
+                        environment: name.clone(),
+                        state: label.clone(),
+                        reference: ident.name(),
+                        commit: file.from_commit.clone().inner(),
+                        reanchored_to: anchor.as_ref().map(|(c, _)| c.clone().inner()),
+                    });
+                    if let Some((commit, message)) = anchor {
+                        reanchored.insert(file.from_commit.clone(), commit.clone());
+                        if fix {
+                            file.from_commit = commit;
+                            file.message = message;
+                            changed.insert(name.clone());
+                        }
+                    }
+                }
+                if !repo.has_commit(&state.head_commit) {
+                    // The head is at least as new as the newest file it contains.
+                    let mut anchor = None;
+                    for file in state.files.values() {
+                        let commit = reanchored
+                            .get(&file.from_commit)
+                            .unwrap_or(&file.from_commit);
+                        if !repo.has_commit(commit) {
+                            anchor = None;
+                            break;
+                        }
+                        let time = repo.commit_time(commit)?;
+                        if anchor.as_ref().map(|(t, _)| time > *t).unwrap_or(true) {
+                            anchor = Some((time, commit.clone()));
+                        }
+                    }
+                    dangling.push(DanglingCommit {
+                        environment: name.clone(),
+                        state: label.clone(),
+                        reference: "head_commit".to_string(),
+                        commit: state.head_commit.clone().inner(),
+                        reanchored_to: anchor.as_ref().map(|(_, c)| c.clone().inner()),
+                    });
+                    if let Some((_, commit)) = anchor {
+                        reanchored.insert(state.head_commit.clone(), commit.clone());
+                        if fix {
+                            state.head_commit = commit;
+                            changed.insert(name.clone());
+                        }
+                    }
+                }
+                let heads = state.propagated_head.iter().map(|head| (None, head)).chain(
+                    state
+                        .propagated_heads
+                        .iter()
+                        .map(|(upstream, head)| (Some(upstream.clone()), head)),
+                );
+                for (upstream, head) in heads {
+                    if !repo.has_commit(head) {
+                        dangling_heads.push((
+                            name.clone(),
+                            idx,
+                            label.clone(),
+                            upstream,
+                            head.clone(),
+                        ));
+                    }
+                }
+            }
+        }
+        // Propagated heads can only be re-anchored once the upstream heads are.
+        for (name, idx, label, upstream, head) in dangling_heads {
+            let anchor = reanchored.get(&head).cloned();
+            dangling.push(DanglingCommit {
+                environment: name.clone(),
+                state: label,
+                reference: match upstream.as_ref() {
+                    Some(upstream) => format!("propagated_heads.{}", upstream),
+                    None => "propagated_head".to_string(),
+                },
+                commit: head.inner(),
+                reanchored_to: anchor.clone().map(|c| c.inner()),
+            });
+            if let (true, Some(commit)) = (fix, anchor) {
+                let env_state = self.state.environments.get_mut(&name).unwrap();
+                let state = match idx {
+                    0 => &mut env_state.current,
+                    idx => &mut env_state.propagation_queue[idx - 1],
+                };
+                match upstream {
+                    Some(upstream) => {
+                        state.propagated_heads.insert(upstream, commit);
+                    }
+                    None => state.propagated_head = Some(commit),
+                }
+                changed.insert(name);
+            }
+        }
+        for name in names.iter().filter(|name| changed.contains(*name)) {
+            self.store.persist(
+                repo,
+                &self.state_dir,
+                scope,
+                name,
+                &self.state.environments[name],
+                true,
+            )?;
+        }
+        Ok(dangling)
+    }
+
+    /// Every recorded version of `env`, newest first.
+    pub fn history(&self, repo: &Repo, env: &str) -> Result<Vec<RecordedState>> {
+        self.store.history(repo, &self.state_dir, env)
+    }
+
+    pub fn state_store(&self) -> StateStoreKind {
+        self.store.kind()
+    }
+
+    /// Resolves the state each upstream environment should propagate to `env`.
+    /// Returns an empty list while any upstream has not been deployed yet.
+    pub fn get_target_propagated_state<'a>(
+        &self,
+        env: &str,
+        env_ignore_queue: bool,
+        upstreams: &'a [(String, Vec<glob::Pattern>)],
+    ) -> Vec<(&'a str, &DeployState)> {
+        let mut ret = Vec::new();
+        for (upstream, patterns) in upstreams.iter() {
+            match self.get_target_state_from(env, env_ignore_queue, upstream, patterns) {
+                Some((_, state, _)) => ret.push((upstream.as_str(), state)),
+                None => return Vec::new(),
+            }
+        }
+        ret
+    }
+
+    /// Like `get_target_propagated_state` but reports which recorded state of each
+    /// upstream environment was selected and why. Upstreams that have not been
+    /// deployed yet are left out.
+    pub fn explain_target_propagated_state(
+        &self,
+        env: &str,
+        env_ignore_queue: bool,
+        upstreams: &[(String, Vec<glob::Pattern>)],
+    ) -> Vec<PropagationTarget> {
+        upstreams
+            .iter()
+            .filter_map(|(upstream, patterns)| {
+                self.get_target_state_from(env, env_ignore_queue, upstream, patterns)
+                    .map(|(idx, state, reason)| PropagationTarget {
+                        upstream: upstream.clone(),
+                        state: match idx {
+                            None => "current".to_string(),
+                            Some(idx) => format!("queue[{}]", idx),
+                        },
+                        head_commit: state.head_commit.clone().inner(),
+                        reason,
+                    })
+            })
+            .collect()
+    }
+
+    /// Fails if the states targeted for propagation disagree on the content
+    /// of a file that is propagated from more than one upstream environment.
+    pub fn ensure_upstreams_agree(
+        &self,
+        env: &str,
+        env_ignore_queue: bool,
+        upstreams: &[(String, Vec<glob::Pattern>)],
+    ) -> Result<()> {
+        let targets = self.get_target_propagated_state(env, env_ignore_queue, upstreams);
+        let mut shared_files: HashMap<String, (&str, &FileHash)> = HashMap::new();
+        for ((upstream, state), (_, patterns)) in targets.iter().zip(upstreams.iter()) {
+            for (ident, file_state) in state.files.iter() {
+                let name = ident.name();
+                let hash = match file_state.file_hash.as_ref() {
+                    Some(hash) => hash,
+                    None => continue,
+                };
+                if !patterns
+                    .iter()
+                    .any(|p| p.matches_with(&name, MATCH_OPTIONS))
+                {
+                    continue;
+                }
+                match shared_files.get(&name) {
+                    Some((other, other_hash)) if other_hash != &hash => {
+                        return Err(CeplerError::UpstreamsDisagree(
+                            env.to_string(),
+                            other.to_string(),
+                            upstream.to_string(),
+                            name,
+                        )
+                        .into());
+                    }
+                    Some(_) => (),
+                    None => {
+                        shared_files.insert(name, (upstream, hash));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The selected state is `current` or the state at the returned position in
+    /// the propagation queue of `propagated_from`.
+    fn get_target_state_from(
         &self,
         env: &str,
         env_ignore_queue: bool,
         propagated_from: &str,
         patterns: &[glob::Pattern],
-    ) -> Option<&DeployState> {
-        let match_options = glob::MatchOptions {
-            case_sensitive: true,
-            require_literal_separator: true,
-            require_literal_leading_dot: true,
-        };
+    ) -> Option<(Option<usize>, &DeployState, TargetReason)> {
         match (
             self.state.environments.get(env),
             self.state.environments.get(propagated_from),
         ) {
             (Some(env), Some(from)) => {
-                if let Some(from_head) = env.current.propagated_head.as_ref() {
-                    if self.ignore_queue
-                        || env_ignore_queue
-                        || from_head == &from.current.head_commit
-                        || from.propagation_queue.is_empty()
-                    {
-                        Some(&from.current)
+                if let Some(from_head) = env.current.propagated_head_from(propagated_from) {
+                    if self.ignore_queue || env_ignore_queue {
+                        Some((None, &from.current, TargetReason::IgnoreQueue))
+                    } else if from_head == &from.current.head_commit {
+                        Some((None, &from.current, TargetReason::UpToDate))
+                    } else if from.propagation_queue.is_empty() {
+                        Some((None, &from.current, TargetReason::EmptyQueue))
                     } else {
-                        let mut ret = &from.current;
-                        for state in from.propagation_queue.iter() {
+                        let mut ret = (None, &from.current, TargetReason::NoQueuedChanges);
+                        for (idx, state) in from.propagation_queue.iter().enumerate() {
                             if &state.head_commit == from_head {
                                 break;
                             }
@@ -155,7 +465,7 @@ impl Database {
                                 let file_name = ident.name();
                                 if patterns
                                     .iter()
-                                    .any(|p| p.matches_with(&file_name, match_options))
+                                    .any(|p| p.matches_with(&file_name, MATCH_OPTIONS))
                                 {
                                     if let Some((_, existing_state)) = env
                                         .current
@@ -164,11 +474,11 @@ impl Database {
                                         .find(|(ident, _)| ident.name() == file_name)
                                     {
                                         if existing_state.file_hash != file_state.file_hash {
-                                            ret = state;
+                                            ret = (Some(idx), state, TargetReason::QueueEntry);
                                             break;
                                         }
                                     } else {
-                                        ret = state;
+                                        ret = (Some(idx), state, TargetReason::QueueEntry);
                                         break;
                                     }
                                 }
@@ -177,10 +487,10 @@ impl Database {
                         Some(ret)
                     }
                 } else {
-                    Some(&from.current)
+                    Some((None, &from.current, TargetReason::FirstPropagation))
                 }
             }
-            (None, Some(state)) => Some(&state.current),
+            (None, Some(state)) => Some((None, &state.current, TargetReason::FirstPropagation)),
             _ => None,
         }
     }
@@ -192,18 +502,13 @@ impl Database {
             .map(|env| (env.version, &env.current))
     }
 
-    fn persist(&self) -> Result<()> {
-        use std::fs;
-        use std::io::Write;
-        let _ = fs::remove_dir_all(&self.state_dir);
-        fs::create_dir_all(&self.state_dir)?;
-        for (name, env) in self.state.environments.iter() {
-            let mut file = File::create(&format!("{}/{}.state", self.state_dir, name))?;
-            let mut bytes = serde_yaml::to_vec(&env)?;
-            bytes.extend("\n".as_bytes());
-            file.write_all(&bytes)?;
-        }
-        Ok(())
+    /// Number of recorded states still waiting to be propagated downstream.
+    pub fn propagation_queue_len(&self, env: &str) -> usize {
+        self.state
+            .environments
+            .get(env)
+            .map(|env| env.propagation_queue.len())
+            .unwrap_or(0)
     }
 }
 
@@ -217,13 +522,10 @@ impl DbState {
         let mut keep_states = 0;
         let to_prune = self.environments.get(&name).unwrap();
         for commit_hash in self.environments.iter().filter_map(|(env_name, state)| {
-            if env_name == &name
-                || state.propagated_from.is_none()
-                || state.propagated_from.as_ref().unwrap() != &name
-            {
+            if env_name == &name || !state.propagated_from.contains(&name) {
                 None
             } else {
-                state.current.propagated_head.as_ref()
+                state.current.propagated_head_from(&name)
             }
         }) {
             if commit_hash == &to_prune.current.head_commit {
@@ -252,18 +554,27 @@ pub struct EnvironmentState {
     #[serde(default)]
     version: u32,
     current: DeployState,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub propagated_from: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default, with = "one_or_many")]
+    pub propagated_from: Vec<String>,
     #[serde(skip_serializing_if = "VecDeque::is_empty")]
     #[serde(default)]
     propagation_queue: VecDeque<DeployState>,
 }
 
 impl EnvironmentState {
-    fn from_reader(reader: impl Read) -> Result<Self> {
+    pub fn from_reader(reader: impl Read) -> Result<Self> {
         let state = serde_yaml::from_reader(reader)?;
         Ok(state)
     }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn current(&self) -> &DeployState {
+        &self.current
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -271,9 +582,16 @@ pub struct DeployState {
     pub head_commit: CommitHash,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub propagated_head: Option<CommitHash>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    #[serde(default)]
+    pub propagated_heads: BTreeMap<String, CommitHash>,
     #[serde(skip_serializing_if = "is_false")]
     #[serde(default)]
     any_dirty: bool,
+    /// The version this state was restored from by a rollback.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub rollback_of: Option<u32>,
     #[serde(default)]
     pub files: BTreeMap<FileIdent, FileState>,
 }
@@ -298,6 +616,14 @@ impl FileIdent {
         !self.0.starts_with("{latest}")
     }
 
+    /// Where the file is taken from: `latest` or the name of the upstream environment.
+    pub fn source(&self) -> &str {
+        self.0
+            .strip_prefix('{')
+            .and_then(|rest| rest.split('}').next())
+            .unwrap_or("latest")
+    }
+
     pub fn inner(self) -> String {
         self.0
     }
@@ -308,11 +634,25 @@ impl DeployState {
         Self {
             head_commit,
             propagated_head: None,
+            propagated_heads: BTreeMap::new(),
             any_dirty: false,
+            rollback_of: None,
             files: BTreeMap::new(),
         }
     }
 
+    pub fn any_dirty(&self) -> bool {
+        self.any_dirty
+    }
+
+    /// The head commit of `upstream` this state was propagated from.
+    /// Environments with a single upstream only record `propagated_head`.
+    pub fn propagated_head_from(&self, upstream: &str) -> Option<&CommitHash> {
+        self.propagated_heads
+            .get(upstream)
+            .or(self.propagated_head.as_ref())
+    }
+
     pub fn diff(&self, other: &DeployState) -> Vec<FileDiff> {
         let mut removed_files: HashSet<&FileIdent> = other.files.keys().collect();
         let mut diffs: Vec<_> = self
@@ -368,6 +708,128 @@ pub struct FileDiff {
     pub added: bool,
 }
 
+impl FileDiff {
+    pub fn change(&self) -> &'static str {
+        if self.added {
+            "added"
+        } else if self.current_state.is_some() {
+            "changed"
+        } else {
+            "removed"
+        }
+    }
+}
+
+impl Serialize for FileDiff {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Diff<'a> {
+            file: String,
+            from: &'a str,
+            change: &'static str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            file_hash: Option<&'a FileHash>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            from_commit: Option<&'a CommitHash>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            message: Option<&'a str>,
+        }
+        let state = self.current_state.as_ref();
+        Diff {
+            file: self.ident.name(),
+            from: self.ident.source(),
+            change: self.change(),
+            file_hash: state.and_then(|s| s.file_hash.as_ref()),
+            from_commit: state.map(|s| &s.from_commit),
+            message: state.map(|s| s.message.as_str()),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Serializes idents as `{ file, from }` objects rather than their state file representation.
+pub fn serialize_idents<S: Serializer>(
+    idents: &[FileIdent],
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    #[derive(Serialize)]
+    struct Ident<'a> {
+        file: String,
+        from: &'a str,
+    }
+    serializer.collect_seq(idents.iter().map(|ident| Ident {
+        file: ident.name(),
+        from: ident.source(),
+    }))
+}
+
+impl fmt::Display for FileDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.ident.name();
+        if self.added {
+            write!(f, "File {} was added", name)
+        } else if self.current_state.is_some() {
+            write!(f, "File {} changed", name)
+        } else {
+            write!(f, "File {} was removed", name)
+        }
+    }
+}
+
+/// The recorded state of an upstream environment that is propagated next
+/// as returned by `Database::explain_target_propagated_state`.
+#[derive(Debug, Serialize)]
+pub struct PropagationTarget {
+    pub upstream: String,
+    /// `current` or the position in the propagation queue (`queue[0]`).
+    pub state: String,
+    pub head_commit: String,
+    pub reason: TargetReason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TargetReason {
+    /// Nothing was propagated from the upstream environment yet.
+    FirstPropagation,
+    /// The queue is disabled via `--ignore-queue` or `ignore_queue` in the config.
+    IgnoreQueue,
+    /// The current state of the upstream environment was already propagated.
+    UpToDate,
+    EmptyQueue,
+    /// The oldest queued state that changes a propagated file.
+    QueueEntry,
+    /// None of the queued states change a propagated file.
+    NoQueuedChanges,
+}
+
+impl fmt::Display for TargetReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            TargetReason::FirstPropagation => "nothing was propagated from it yet",
+            TargetReason::IgnoreQueue => "the propagation queue is ignored",
+            TargetReason::UpToDate => "its current state was already propagated",
+            TargetReason::EmptyQueue => "its propagation queue is empty",
+            TargetReason::QueueEntry => "oldest queued state that changes a propagated file",
+            TargetReason::NoQueuedChanges => "no queued state changes a propagated file",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// A commit referenced by a recorded state that doesn't exist in the repository.
+#[derive(Debug, Serialize)]
+pub struct DanglingCommit {
+    pub environment: String,
+    /// `current` or the position in the propagation queue (`queue[0]`).
+    pub state: String,
+    /// The file, `head_commit` or propagated head that references the commit.
+    pub reference: String,
+    pub commit: String,
+    /// The existing commit the reference was (or with `--fix` would be) moved to.
+    pub reanchored_to: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileState {
     pub file_hash: Option<FileHash>,
@@ -389,6 +851,16 @@ impl fmt::Display for FileState {
     }
 }
 
+/// 0.5s, 1s, 2s, ... capped at 8s, plus up to 250ms of jitter so that
+/// pipelines that collided once don't collide again on the next attempt.
+fn push_backoff(attempt: u32) -> Duration {
+    let jitter = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_millis() % 250)
+        .unwrap_or(0);
+    Duration::from_millis((500 << attempt.min(4)) + jitter as u64)
+}
+
 fn is_false(b: &bool) -> bool {
     !b
 }
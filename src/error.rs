@@ -0,0 +1,126 @@
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, CeplerError>;
+
+/// Errors returned by the public api of cepler.
+///
+/// Internally cepler uses `anyhow` and raises the domain specific variants via `.into()`
+/// so that they can be recovered at the api boundary.
+#[derive(Debug, Error)]
+pub enum CeplerError {
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("Environment '{0}' not found in config")]
+    EnvironmentNotFound(String),
+    #[error("Environment '{0}' is missing in gates file")]
+    GateNotFound(String),
+    #[error("Previous environment '{0}' not deployed yet")]
+    PreviousEnvironmentNotDeployed(String),
+    #[error("Upstream environments '{1}' and '{2}' of '{0}' disagree on the content of '{3}'")]
+    UpstreamsDisagree(String, String, String, String),
+    #[error("No state recorded for '{0}'")]
+    NoStateRecorded(String),
+    #[error("Version {1} of '{0}' was never recorded")]
+    VersionNotFound(String, u32),
+    #[error(
+        "State is locked by another cepler process (remove '{0}' if no other process is running)"
+    )]
+    StateLocked(String),
+    #[error("Host key verification failed: {0}")]
+    HostKeyRejected(String),
+    #[error("Push was rejected by the remote ({0})")]
+    PushRejected(String),
+    #[error(
+        "'{0}' was recorded concurrently: expected version {1} on the remote but found version {2}"
+    )]
+    ConcurrentRecord(String, u32, u32),
+    #[error("Couldn't resolve revision '{0}'")]
+    InvalidRevision(String),
+    #[error("Commit {1} that '{0}' was recorded from no longer exists and its content is gone (run `cepler doctor`)")]
+    DanglingCommit(String, String),
+    #[error("{message}")]
+    Git {
+        message: String,
+        #[source]
+        source: git2::Error,
+    },
+    #[error("{message}")]
+    Io {
+        message: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("{message}")]
+    Yaml {
+        message: String,
+        #[source]
+        source: serde_yaml::Error,
+    },
+    #[error("{0}")]
+    Other(String),
+}
+
+impl From<anyhow::Error> for CeplerError {
+    fn from(e: anyhow::Error) -> Self {
+        let contexts: Vec<_> = e.chain().map(|c| c.to_string()).collect();
+        let context = |kind: &str| {
+            if contexts.len() > 1 {
+                contexts[..contexts.len() - 1].join(": ")
+            } else {
+                kind.to_string()
+            }
+        };
+        let e = match e.downcast::<CeplerError>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+        let e = match e.downcast::<git2::Error>() {
+            Ok(source) => {
+                return CeplerError::Git {
+                    message: context("Git error"),
+                    source,
+                }
+            }
+            Err(e) => e,
+        };
+        let e = match e.downcast::<std::io::Error>() {
+            Ok(source) => {
+                return CeplerError::Io {
+                    message: context("IO error"),
+                    source,
+                }
+            }
+            Err(e) => e,
+        };
+        match e.downcast::<serde_yaml::Error>() {
+            Ok(source) => CeplerError::Yaml {
+                message: context("Couldn't parse yaml"),
+                source,
+            },
+            Err(e) => CeplerError::Other(format!("{:#}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn recovers_typed_errors() {
+        let e = anyhow::Error::from(CeplerError::NoStateRecorded("staging".to_string()));
+        assert!(matches!(
+            CeplerError::from(e.context("Couldn't reproduce")),
+            CeplerError::NoStateRecorded(name) if name == "staging"
+        ));
+
+        let e = Err::<(), _>(git2::Error::from_str("not found"))
+            .context("Couldn't fetch origin")
+            .unwrap_err();
+        assert!(matches!(
+            CeplerError::from(e),
+            CeplerError::Git { message, .. } if message == "Couldn't fetch origin"
+        ));
+    }
+}
@@ -0,0 +1,275 @@
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+/// The entries of an OpenSSH `known_hosts` file.
+///
+/// Supports plain and hashed (`|1|salt|hash`) host names, `[host]:port`,
+/// `*`/`?` wildcards, negated patterns and `@revoked` keys.
+/// `@cert-authority` lines are ignored as libgit2 doesn't hand out certificates.
+pub struct KnownHosts {
+    entries: Vec<Entry>,
+}
+
+struct Entry {
+    revoked: bool,
+    hosts: Vec<HostPattern>,
+    key: Vec<u8>,
+}
+
+enum HostPattern {
+    Plain {
+        pattern: glob::Pattern,
+        negated: bool,
+    },
+    Hashed {
+        salt: Vec<u8>,
+        hash: Vec<u8>,
+    },
+}
+
+/// The fingerprint of the key presented by a host.
+pub enum HostKey<'a> {
+    Sha256(&'a [u8]),
+    Sha1(&'a [u8]),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HostKeyStatus {
+    Match,
+    Mismatch,
+    Revoked,
+    Unknown,
+}
+
+impl KnownHosts {
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut entries = Vec::new();
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let mut hosts = fields.next().unwrap_or_default();
+            let mut revoked = false;
+            if hosts.starts_with('@') {
+                match hosts {
+                    "@revoked" => revoked = true,
+                    "@cert-authority" => continue,
+                    marker => return Err(anyhow!("Unknown marker '{}' in line {}", marker, n + 1)),
+                }
+                hosts = fields.next().unwrap_or_default();
+            }
+            let key = match (fields.next(), fields.next()) {
+                (Some(_), Some(key)) => base64::decode(key)
+                    .map_err(|e| anyhow!("Invalid key in line {}: {}", n + 1, e))?,
+                _ => return Err(anyhow!("Missing key in line {}", n + 1)),
+            };
+            entries.push(Entry {
+                revoked,
+                hosts: hosts
+                    .split(',')
+                    .map(HostPattern::parse)
+                    .collect::<Result<_>>()
+                    .map_err(|e| anyhow!("{} in line {}", e, n + 1))?,
+                key,
+            });
+        }
+        Ok(Self { entries })
+    }
+
+    /// Checks the key presented by `host` (listening on `port`) against the entries.
+    pub fn check(&self, host: &str, port: u16, key: HostKey) -> HostKeyStatus {
+        let name = if port == 22 {
+            host.to_string()
+        } else {
+            format!("[{}]:{}", host, port)
+        };
+        let mut status = HostKeyStatus::Unknown;
+        for entry in self.entries.iter().filter(|e| e.matches(&name)) {
+            let same_key = match key {
+                HostKey::Sha256(hash) => Sha256::digest(&entry.key).as_slice() == hash,
+                HostKey::Sha1(hash) => Sha1::digest(&entry.key).as_slice() == hash,
+            };
+            match (same_key, entry.revoked) {
+                (true, true) => return HostKeyStatus::Revoked,
+                (true, false) => status = HostKeyStatus::Match,
+                (false, false) if status == HostKeyStatus::Unknown => {
+                    status = HostKeyStatus::Mismatch
+                }
+                _ => (),
+            }
+        }
+        status
+    }
+}
+
+impl Entry {
+    fn matches(&self, name: &str) -> bool {
+        let mut matched = false;
+        for host in self.hosts.iter() {
+            match host {
+                HostPattern::Plain { pattern, negated } if pattern.matches(name) => {
+                    if *negated {
+                        return false;
+                    }
+                    matched = true;
+                }
+                HostPattern::Hashed { salt, hash } => {
+                    let mut mac =
+                        Hmac::<Sha1>::new_from_slice(salt).expect("Hmac accepts any key size");
+                    mac.update(name.as_bytes());
+                    if mac.verify_slice(hash).is_ok() {
+                        matched = true;
+                    }
+                }
+                _ => (),
+            }
+        }
+        matched
+    }
+}
+
+impl HostPattern {
+    fn parse(host: &str) -> Result<Self> {
+        if let Some(hashed) = host.strip_prefix("|1|") {
+            let (salt, hash) = hashed
+                .split_once('|')
+                .ok_or_else(|| anyhow!("Invalid hashed host '{}'", host))?;
+            let decode =
+                |s| base64::decode(s).map_err(|_| anyhow!("Invalid hashed host '{}'", host));
+            return Ok(HostPattern::Hashed {
+                salt: decode(salt)?,
+                hash: decode(hash)?,
+            });
+        }
+        let (host, negated) = match host.strip_prefix('!') {
+            Some(host) => (host, true),
+            None => (host, false),
+        };
+        let pattern = glob::Pattern::new(
+            &glob::Pattern::escape(host)
+                .replace("[*]", "*")
+                .replace("[?]", "?"),
+        )
+        .map_err(|_| anyhow!("Invalid host pattern '{}'", host))?;
+        Ok(HostPattern::Plain { pattern, negated })
+    }
+}
+
+/// The port of an ssh remote or `None` if `url` doesn't use ssh.
+pub fn ssh_port(url: &str) -> Option<u16> {
+    if let Some((scheme, rest)) = url.split_once("://") {
+        if scheme != "ssh" && scheme != "git+ssh" && scheme != "ssh+git" {
+            return None;
+        }
+        let authority = rest.split('/').next().unwrap_or_default();
+        let host = authority.rsplit('@').next().unwrap_or_default();
+        return Some(match host.rsplit_once(':') {
+            Some((_, port)) if !host.ends_with(']') => port.parse().unwrap_or(22),
+            _ => 22,
+        });
+    }
+    // scp like syntax: [user@]host:path
+    match url.split_once(':') {
+        Some((host, _)) if !host.contains('/') => Some(22),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl";
+    const OTHER_KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIAfuCHKVTjquxvt6CM6tdG4SLp1Btn/nOeHHE5UOzRdf";
+
+    fn sha256(key: &str) -> Vec<u8> {
+        Sha256::digest(base64::decode(key).unwrap()).to_vec()
+    }
+
+    #[test]
+    fn checks_plain_hosts() {
+        let hosts = KnownHosts::parse(&format!(
+            "# comment\ngithub.com,140.82.121.4 ssh-ed25519 {}\n[git.example.com]:2222 ssh-ed25519 {}\n",
+            KEY, OTHER_KEY
+        ))
+        .unwrap();
+        let key = sha256(KEY);
+        let other = sha256(OTHER_KEY);
+        assert_eq!(
+            hosts.check("github.com", 22, HostKey::Sha256(&key)),
+            HostKeyStatus::Match
+        );
+        assert_eq!(
+            hosts.check("140.82.121.4", 22, HostKey::Sha256(&other)),
+            HostKeyStatus::Mismatch
+        );
+        assert_eq!(
+            hosts.check("git.example.com", 2222, HostKey::Sha256(&other)),
+            HostKeyStatus::Match
+        );
+        assert_eq!(
+            hosts.check("git.example.com", 22, HostKey::Sha256(&other)),
+            HostKeyStatus::Unknown
+        );
+    }
+
+    #[test]
+    fn checks_hashed_and_wildcard_hosts() {
+        // github.com hashed as by `ssh-keygen -H`
+        let hosts = KnownHosts::parse(&format!(
+            "|1|F1E1KeoE/eEWhi10WpGv4OdiO6Y=|J/ckLITMEvhtGoBa9QKaS5Nl1ds= ssh-ed25519 {}\n*.example.com,!bad.example.com ssh-ed25519 {}\n@revoked old.example.com ssh-ed25519 {}\n",
+            KEY, OTHER_KEY, OTHER_KEY
+        ))
+        .unwrap();
+        let key = sha256(KEY);
+        let other = sha256(OTHER_KEY);
+        assert_eq!(
+            hosts.check("github.com", 22, HostKey::Sha256(&key)),
+            HostKeyStatus::Match
+        );
+        assert_eq!(
+            hosts.check("git.example.com", 22, HostKey::Sha256(&other)),
+            HostKeyStatus::Match
+        );
+        assert_eq!(
+            hosts.check("bad.example.com", 22, HostKey::Sha256(&other)),
+            HostKeyStatus::Unknown
+        );
+        assert_eq!(
+            hosts.check("old.example.com", 22, HostKey::Sha256(&other)),
+            HostKeyStatus::Revoked
+        );
+        assert_eq!(
+            hosts.check(
+                "git.example.com",
+                22,
+                HostKey::Sha1(&Sha1::digest(base64::decode(OTHER_KEY).unwrap()))
+            ),
+            HostKeyStatus::Match
+        );
+    }
+
+    #[test]
+    fn detects_ssh_urls() {
+        assert_eq!(ssh_port("git@github.com:bodymindarts/cepler.git"), Some(22));
+        assert_eq!(
+            ssh_port("ssh://git@git.example.com:2222/repo.git"),
+            Some(2222)
+        );
+        assert_eq!(ssh_port("ssh://git.example.com/repo.git"), Some(22));
+        assert_eq!(ssh_port("https://github.com/bodymindarts/cepler.git"), None);
+        assert_eq!(ssh_port("file:///tmp/repo.git"), None);
+        assert_eq!(ssh_port("/tmp/repo.git"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(KnownHosts::parse("github.com ssh-ed25519").is_err());
+        assert!(KnownHosts::parse("github.com ssh-ed25519 not-base64!").is_err());
+        assert!(KnownHosts::parse("@unknown github.com ssh-ed25519 AAAA").is_err());
+    }
+}
@@ -1,10 +1,29 @@
 #![cfg_attr(feature = "fail-on-warnings", deny(warnings))]
 #![cfg_attr(feature = "fail-on-warnings", deny(clippy::all))]
 
+mod cache;
 mod concourse;
 mod config;
 mod database;
+mod error;
+mod known_hosts;
 mod repo;
+mod state_store;
+mod validate;
 mod workspace;
 
 pub mod cli;
+
+pub use config::{Config, EnvironmentConfig, GatesConfig, HistoryMode, RepoConfig, StateStoreKind};
+pub use database::{
+    DanglingCommit, DeployState, FileDiff, FileIdent, FileState, PropagationTarget, TargetReason,
+};
+pub use error::{CeplerError, Result};
+pub use repo::{CommitHash, FileChange, FileHash, GitConfig, GitCredentials, HostKeyCheck};
+pub use validate::{validate, Diagnostic};
+pub use workspace::{
+    CheckResult, ComparedFile, Comparison, ContentDiff, EnvironmentImpact, EnvironmentStatus,
+    Explanation, FileComparison, Graph, GraphEdge, HistoryEntry, HistoryOptions, Impact,
+    PrepareResult, Preview, RecordOptions, RecordResult, ReproduceResult, StateId, UpstreamFile,
+    Workspace, WorkspaceBuilder,
+};
@@ -1,5 +1,6 @@
 use cepler::cli;
+use std::process::ExitCode;
 
-fn main() -> Result<(), anyhow::Error> {
+fn main() -> Result<ExitCode, anyhow::Error> {
     cli::run()
 }
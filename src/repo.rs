@@ -1,22 +1,30 @@
-use super::config::{default_scope, MATCH_OPTIONS};
+use super::{
+    config::{default_scope, HistoryMode, MATCH_OPTIONS},
+    error::CeplerError,
+    known_hosts::{ssh_port, HostKey, HostKeyStatus, KnownHosts},
+};
 use anyhow::{Context, Result};
 use git2::{
-    build::CheckoutBuilder, BranchType, Commit, Cred, MergeOptions, Object, ObjectType, Oid,
-    PushOptions, RebaseOptions, RemoteCallbacks, Repository, ResetType, Signature, TreeWalkMode,
-    TreeWalkResult,
+    build::{CheckoutBuilder, TreeUpdateBuilder},
+    cert::Cert,
+    BranchType, Commit, Cred, CredentialType, FileMode, MergeOptions, Object, ObjectType, Oid,
+    PushOptions, RebaseOptions, RemoteCallbacks, Repository, ResetType, Signature, Sort,
+    TreeWalkMode, TreeWalkResult,
 };
 use glob::*;
 use serde::{Deserialize, Serialize};
 use std::{
+    cell::RefCell,
     collections::{HashSet, VecDeque},
     fmt,
     path::{Path, PathBuf},
+    rc::Rc,
 };
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct FileHash(String);
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct CommitHash(String);
 impl fmt::Display for CommitHash {
@@ -34,25 +42,113 @@ impl CommitHash {
     }
 }
 
-pub fn hash_file<P: AsRef<Path>>(file: P) -> Option<FileHash> {
-    let path = file.as_ref();
-    if path.is_file() {
-        Some(FileHash(
-            Oid::hash_file(ObjectType::Blob, path)
-                .expect("Couldn't hash object")
-                .to_string(),
-        ))
-    } else {
-        None
-    }
-}
-
+#[derive(Clone)]
 pub struct GitConfig {
     pub url: String,
     pub branch: String,
     pub gates_branch: Option<String>,
-    pub private_key: String,
+    pub credentials: GitCredentials,
     pub dir: String,
+    /// Only fetch this many commits of history when cloning or pulling.
+    pub depth: Option<u32>,
+}
+
+/// How to authenticate against the remote.
+///
+/// The host key of ssh remotes is verified according to `host_key_check`.
+/// SSH remotes use `private_key` (or the key at `private_key_path`) unlocked with `passphrase`.
+/// HTTPS remotes use `username` and `password`, which may also be an access token.
+/// Without a password the git credential helpers configured for the user are asked.
+#[derive(Clone, Default)]
+pub struct GitCredentials {
+    pub host_key_check: HostKeyCheck,
+    pub private_key: Option<String>,
+    pub private_key_path: Option<PathBuf>,
+    pub passphrase: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+/// How to verify the host key presented by ssh remotes.
+#[derive(Clone, Default)]
+pub enum HostKeyCheck {
+    /// Verify against `~/.ssh/known_hosts`.
+    #[default]
+    UserKnownHosts,
+    /// Verify against the given content of a `known_hosts` file.
+    KnownHosts(String),
+    /// Accept any host key.
+    Insecure,
+}
+
+/// Username used with a token when no username is configured.
+/// Most git servers accept any username together with a personal access token.
+pub const TOKEN_USERNAME: &str = "x-access-token";
+
+/// A commit on a ref together with its time and the blobs it added or changed.
+pub type RefChange = (CommitHash, i64, Vec<Vec<u8>>);
+
+/// The subtrees of a commit that can contain files matching a set of glob patterns.
+/// Derived from the literal leading directories of each pattern so tree walks
+/// don't have to descend into unrelated parts of the repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pathspec {
+    prefixes: Vec<PathBuf>,
+}
+
+impl Pathspec {
+    /// The whole tree.
+    pub fn all() -> Self {
+        Self {
+            prefixes: vec![PathBuf::new()],
+        }
+    }
+
+    pub fn from_patterns<'a>(patterns: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut prefixes: Vec<PathBuf> = patterns
+            .into_iter()
+            .map(|pattern| {
+                Path::new(pattern)
+                    .components()
+                    .take_while(|c| {
+                        !c.as_os_str()
+                            .to_string_lossy()
+                            .contains(&['*', '?', '['][..])
+                    })
+                    .collect()
+            })
+            .collect();
+        prefixes.sort();
+        let mut kept: Vec<PathBuf> = Vec::new();
+        for prefix in prefixes {
+            if !kept.iter().any(|k| prefix.starts_with(k)) {
+                kept.push(prefix);
+            }
+        }
+        Self { prefixes: kept }
+    }
+}
+
+/// Why `find_last_changed_commit` stopped at a commit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileChange {
+    /// None of the parents contain the file.
+    Added,
+    /// The parents contain a different version of the file.
+    Modified,
+    /// The commit has no parents or they are missing from a shallow clone.
+    FirstCommit,
+}
+
+impl fmt::Display for FileChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileChange::Added => write!(f, "the file was added"),
+            FileChange::Modified => write!(f, "the file was modified"),
+            FileChange::FirstCommit => write!(f, "it is the first commit of the history"),
+        }
+    }
 }
 
 pub struct Repo {
@@ -65,19 +161,23 @@ impl Repo {
         GitConfig {
             url,
             branch,
-            private_key,
+            credentials,
             dir,
+            depth,
             ..
         }: GitConfig,
     ) -> Result<Self> {
-        let callbacks = remote_callbacks(private_key);
+        let (callbacks, host_key) = remote_callbacks(credentials, &url);
         let mut fo = git2::FetchOptions::new();
         fo.remote_callbacks(callbacks);
+        if let Some(depth) = depth {
+            fo.depth(depth as i32);
+        }
 
         let mut builder = git2::build::RepoBuilder::new();
         builder.fetch_options(fo);
         builder.branch(&branch);
-        let inner = builder.clone(&url, Path::new(&dir))?;
+        let inner = host_key.check(builder.clone(&url, Path::new(&dir)))?;
         Ok(Self { inner, gate: None })
     }
 
@@ -86,19 +186,23 @@ impl Repo {
         GitConfig {
             branch,
             gates_branch,
-            private_key,
+            credentials,
+            depth,
             ..
         }: GitConfig,
     ) -> Result<()> {
-        let callbacks = remote_callbacks(private_key);
+        let mut remote = self.inner.find_remote("origin")?;
+        let (callbacks, host_key) = remote_callbacks(credentials, remote.url().unwrap_or_default());
         let mut fo = git2::FetchOptions::new();
         fo.remote_callbacks(callbacks);
-        let mut remote = self.inner.find_remote("origin")?;
+        if let Some(depth) = depth {
+            fo.depth(depth as i32);
+        }
         let mut branches = vec![branch.clone()];
         if let Some(gates) = gates_branch {
             branches.push(gates);
         }
-        remote.fetch(&branches, Some(&mut fo), None)?;
+        host_key.check(remote.fetch(&branches, Some(&mut fo), None))?;
         let suffix = format!("/{}", branch);
         let remote_head = remote
             .list()?
@@ -112,22 +216,41 @@ impl Repo {
         Ok(())
     }
 
-    pub fn push(
+    /// Fetches `branch` from origin and returns the commit it points to.
+    pub fn fetch_branch(
         &self,
         GitConfig {
             branch,
-            private_key,
+            credentials,
             ..
-        }: GitConfig,
-    ) -> Result<bool> {
-        let callbacks = remote_callbacks(private_key.clone());
+        }: &GitConfig,
+    ) -> Result<CommitHash> {
+        let mut remote = self.inner.find_remote("origin")?;
+        let (callbacks, host_key) =
+            remote_callbacks(credentials.clone(), remote.url().unwrap_or_default());
         let mut fo = git2::FetchOptions::new();
         fo.remote_callbacks(callbacks);
-        let mut remote = self.inner.find_remote("origin")?;
-        remote
-            .fetch(&[branch.clone()], Some(&mut fo), None)
+        host_key
+            .check(remote.fetch(std::slice::from_ref(branch), Some(&mut fo), None))
             .context("Couldn't fetch origin")?;
+        let commit = self
+            .inner
+            .resolve_reference_from_short_name(&format!("origin/{}", branch))
+            .context("Couldn't resolve remote branch")?
+            .peel_to_commit()?;
+        Ok(CommitHash(commit.id().to_string()))
+    }
 
+    /// Rebases HEAD onto the last fetched state of `branch` and pushes it.
+    /// Call `fetch_branch` first.
+    pub fn push(
+        &self,
+        GitConfig {
+            branch,
+            credentials,
+            ..
+        }: GitConfig,
+    ) -> Result<bool> {
         let annotated_head = self
             .inner
             .reference_to_annotated_commit(&self.inner.head()?)
@@ -179,26 +302,20 @@ impl Repo {
         rebase.finish(None).context("Couldn't finish rebase")?;
 
         if n_applied > 0 {
-            let mut push_options = PushOptions::new();
-            push_options.remote_callbacks(remote_callbacks(private_key));
-            remote
-                .push(
-                    &[format!(
-                        "{}:{}",
-                        head_commit.refname().unwrap(),
-                        head_commit.refname().unwrap(),
-                    )],
-                    Some(&mut push_options),
-                )
-                .context("Couldn't push to remote")?;
+            let refname = head_commit.refname().unwrap();
+            self.push_refspec(&credentials, &format!("{}:{}", refname, refname))?;
             Ok(true)
         } else {
             Ok(false)
         }
     }
 
-    pub fn open(gate: Option<String>) -> Result<Self> {
-        let inner = Repository::open_from_env()?;
+    pub fn open(path: Option<&Path>, gate: Option<String>) -> Result<Self> {
+        let inner = if let Some(path) = path {
+            Repository::open(path).context("Couldn't open repository")?
+        } else {
+            Repository::open_from_env()?
+        };
         let gate = if let Some(gate) = gate {
             let commit = Oid::from_str(&gate).context("Gate is not a valid commit hash")?;
             inner
@@ -211,6 +328,32 @@ impl Repo {
         Ok(Self { inner, gate })
     }
 
+    /// The root of the working tree. All paths handled by cepler are relative to it.
+    pub fn workdir(&self) -> &Path {
+        self.inner
+            .workdir()
+            .expect("Bare repositories are not supported")
+    }
+
+    /// The `.git` directory of the repository.
+    pub fn git_dir(&self) -> &Path {
+        self.inner.path()
+    }
+
+    /// Hashes the file at `file` (relative to the workdir) as it is on disk.
+    pub fn hash_file<P: AsRef<Path>>(&self, file: P) -> Option<FileHash> {
+        let path = self.workdir().join(file);
+        if path.is_file() {
+            Some(FileHash(
+                Oid::hash_file(ObjectType::Blob, &path)
+                    .expect("Couldn't hash object")
+                    .to_string(),
+            ))
+        } else {
+            None
+        }
+    }
+
     pub fn commit_state_file(&self, scope: &str, file_name: String) -> Result<()> {
         let path = Path::new(&file_name);
         let mut index = self.inner.index()?;
@@ -256,7 +399,8 @@ impl Repo {
                 .any(|p| p.matches_path_with(file, MATCH_OPTIONS))
         };
         let mut paths = Vec::new();
-        self.all_files(self.gate_commit_hash(), |_, path| {
+        let pathspec = Pathspec::from_patterns(globs.iter().map(|p| p.as_str()));
+        self.all_files(self.gate_commit_hash(), &pathspec, |_, path| {
             if !ignore(path) && includes(path) {
                 paths.push(path.to_path_buf())
             }
@@ -266,26 +410,82 @@ impl Repo {
         paths.into_iter()
     }
 
-    pub fn all_files<F>(&self, commit: CommitHash, mut f: F) -> Result<()>
+    /// Calls `f` for every file below `pathspec` in `commit`.
+    pub fn all_files<F>(&self, commit: CommitHash, pathspec: &Pathspec, mut f: F) -> Result<()>
     where
         F: FnMut(FileHash, &Path) -> Result<()>,
     {
         let commit = Oid::from_str(&commit.0).expect("Couldn't parse commit hash");
         let commit = self.inner.find_commit(commit)?;
         let tree = commit.tree().context("Couldn't resolve tree")?;
-        let mut ret = Ok(());
-        tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
-            let path_name = format!("{}{}", dir, entry.name().expect("Entry has no name"));
-            let path = Path::new(&path_name);
-            if let Some(ObjectType::Blob) = entry.kind() {
-                if let Err(e) = f(FileHash(entry.id().to_string()), path) {
-                    ret = Err(e);
-                    return TreeWalkResult::Abort;
+        for prefix in pathspec.prefixes.iter() {
+            let subtree = if prefix.as_os_str().is_empty() {
+                tree.clone()
+            } else {
+                let entry = match tree.get_path(prefix) {
+                    Ok(entry) => entry,
+                    Err(_) => continue,
+                };
+                match entry.kind() {
+                    Some(ObjectType::Blob) => {
+                        f(FileHash(entry.id().to_string()), prefix)?;
+                        continue;
+                    }
+                    Some(ObjectType::Tree) => self.inner.find_tree(entry.id())?,
+                    _ => continue,
                 }
-            }
-            TreeWalkResult::Ok
-        })?;
-        ret
+            };
+            let dir_prefix = if prefix.as_os_str().is_empty() {
+                String::new()
+            } else {
+                format!("{}/", prefix.display())
+            };
+            let mut ret = Ok(());
+            subtree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+                let path_name = format!(
+                    "{}{}{}",
+                    dir_prefix,
+                    dir,
+                    entry.name().expect("Entry has no name")
+                );
+                let path = Path::new(&path_name);
+                if let Some(ObjectType::Blob) = entry.kind() {
+                    if let Err(e) = f(FileHash(entry.id().to_string()), path) {
+                        ret = Err(e);
+                        return TreeWalkResult::Abort;
+                    }
+                }
+                TreeWalkResult::Ok
+            })?;
+            ret?;
+        }
+        Ok(())
+    }
+
+    /// The ids of the entries at the prefixes of `pathspec` in `commit`.
+    /// Commits with equal ids have identical content below `pathspec`.
+    pub fn tree_ids(
+        &self,
+        commit: &CommitHash,
+        pathspec: &Pathspec,
+    ) -> Result<Vec<Option<String>>> {
+        let commit = Oid::from_str(&commit.0).expect("Couldn't parse commit hash");
+        let tree = self
+            .inner
+            .find_commit(commit)?
+            .tree()
+            .context("Couldn't resolve tree")?;
+        Ok(pathspec
+            .prefixes
+            .iter()
+            .map(|prefix| {
+                if prefix.as_os_str().is_empty() {
+                    Some(tree.id().to_string())
+                } else {
+                    tree.get_path(prefix).ok().map(|e| e.id().to_string())
+                }
+            })
+            .collect())
     }
 
     fn is_trackable_file(&self, file: &Path) -> bool {
@@ -298,6 +498,12 @@ impl Repo {
             .expect("Cannot check ignore status")
     }
 
+    pub fn head_commit_hash(&self) -> Result<CommitHash> {
+        Ok(CommitHash(
+            self.inner.head()?.peel_to_commit()?.id().to_string(),
+        ))
+    }
+
     pub fn gate_commit_hash(&self) -> CommitHash {
         CommitHash(self.gate_oid().to_string())
     }
@@ -324,6 +530,83 @@ impl Repo {
         Ok(())
     }
 
+    pub fn has_commit(&self, commit: &CommitHash) -> bool {
+        Oid::from_str(&commit.0)
+            .map(|oid| self.inner.find_commit(oid).is_ok())
+            .unwrap_or(false)
+    }
+
+    pub fn has_blob(&self, hash: &FileHash) -> bool {
+        Oid::from_str(&hash.0)
+            .map(|oid| self.inner.find_blob(oid).is_ok())
+            .unwrap_or(false)
+    }
+
+    /// Writes the blob `hash` to `path` in the working directory.
+    /// Used when the commit a file was recorded from no longer exists.
+    pub fn checkout_blob(&self, path: &str, hash: &FileHash) -> Result<()> {
+        let blob = self
+            .inner
+            .find_blob(Oid::from_str(&hash.0).expect("Couldn't parse Oid"))?;
+        let path = self.workdir().join(path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, blob.content())
+            .with_context(|| format!("Couldn't write '{}'", path.display()))?;
+        Ok(())
+    }
+
+    /// Unified diff of `path` between the blobs `old` and `new`. Missing blobs are treated
+    /// as empty. Returns the patch together with the number of inserted and deleted lines.
+    pub fn diff_blobs(
+        &self,
+        path: &str,
+        old: Option<&FileHash>,
+        new: Option<&FileHash>,
+    ) -> Result<(String, usize, usize)> {
+        let content = |hash: Option<&FileHash>| -> Vec<u8> {
+            hash.and_then(|hash| Oid::from_str(&hash.0).ok())
+                .and_then(|oid| self.inner.find_blob(oid).ok())
+                .map(|blob| blob.content().to_vec())
+                .unwrap_or_default()
+        };
+        let (old, new) = (content(old), content(new));
+        let path = Path::new(path);
+        let mut patch = git2::Patch::from_buffers(&old, Some(path), &new, Some(path), None)?;
+        let (_, insertions, deletions) = patch.line_stats()?;
+        let text = patch.to_buf()?.as_str().unwrap_or_default().to_string();
+        Ok((text, insertions, deletions))
+    }
+
+    /// The commit that introduced the content `hash` at `path` in the history of any ref.
+    pub fn find_commit_with_blob(
+        &self,
+        path: &Path,
+        hash: &FileHash,
+    ) -> Result<Option<(CommitHash, String)>> {
+        let target = Oid::from_str(&hash.0)?;
+        let mut walk = self.inner.revwalk()?;
+        walk.push_glob("refs/*")?;
+        walk.set_sorting(Sort::TIME)?;
+        for oid in walk {
+            let commit = self.inner.find_commit(oid?)?;
+            let found = commit
+                .tree()?
+                .get_path(path)
+                .map(|entry| entry.id() == target)
+                .unwrap_or(false);
+            if found {
+                return Ok(Some(self.find_last_changed_commit(
+                    path,
+                    CommitHash(commit.id().to_string()),
+                    HistoryMode::All,
+                )?));
+            }
+        }
+        Ok(None)
+    }
+
     pub fn checkout_gate(
         &self,
         globs: &[Pattern],
@@ -339,10 +622,17 @@ impl Repo {
             checkout.path(path);
         }
 
-        for path in glob("**/*").expect("List all files") {
-            let path = path.expect("Get file");
-            if self.is_trackable_file(&path) {
-                let path = path.as_path();
+        let workdir = self.workdir();
+        let all_files = format!(
+            "{}/**/*",
+            Pattern::escape(workdir.to_string_lossy().trim_end_matches('/'))
+        );
+        for full_path in glob(&all_files).expect("List all files") {
+            let full_path = full_path.expect("Get file");
+            let path = full_path
+                .strip_prefix(workdir)
+                .expect("File outside of workdir");
+            if self.is_trackable_file(path) {
                 let check = |p: &glob::Pattern| {
                     p.matches_path_with(
                         path,
@@ -354,10 +644,10 @@ impl Repo {
                     )
                 };
                 if !ignore_files.iter().any(check)
-                    && path.is_file()
+                    && full_path.is_file()
                     && (clean || globs.iter().any(check))
                 {
-                    std::fs::remove_file(path).expect("Couldn't remove file");
+                    std::fs::remove_file(&full_path).expect("Couldn't remove file");
                 }
             }
         }
@@ -377,7 +667,14 @@ impl Repo {
         Ok(())
     }
 
-    pub fn walk_commits_before<F>(&self, commit: CommitHash, mut cb: F) -> Result<()>
+    /// Walks back from `commit` for as long as `cb` returns `true`. Returns the commit at
+    /// which the walk ran into the boundary of a shallow clone, if any.
+    pub fn walk_commits_before<F>(
+        &self,
+        commit: CommitHash,
+        history: HistoryMode,
+        mut cb: F,
+    ) -> Result<Option<CommitHash>>
     where
         F: FnMut(CommitHash) -> Result<bool>,
     {
@@ -385,33 +682,104 @@ impl Repo {
         let commit = self.inner.find_commit(commit)?;
         let mut set = HashSet::new();
         let mut queue = VecDeque::new();
+        let mut boundary = None;
+        let shallow_roots = self.shallow_roots();
         set.insert(commit.id());
-        for parent in commit.parents() {
-            if set.insert(parent.id()) {
-                queue.push_back(parent);
-            }
-        }
-        loop {
-            if queue.is_empty() {
+        queue.push_back(commit);
+        let mut first = true;
+        while let Some(commit) = queue.pop_front() {
+            if !first && !cb(CommitHash(commit.id().to_string()))? {
                 break;
             }
-            let commit = queue.pop_front().unwrap();
-            if !cb(CommitHash(commit.id().to_string()))? {
-                break;
+            first = false;
+            if boundary.is_none()
+                && (shallow_roots.contains(&commit.id()) || has_missing_parents(&commit, history))
+            {
+                boundary = Some(CommitHash(commit.id().to_string()));
             }
-            for parent in commit.parents() {
+            for parent in parents(&commit, history) {
                 if set.insert(parent.id()) {
                     queue.push_back(parent);
                 }
             }
         }
-        Ok(())
+        Ok(boundary)
+    }
+
+    /// Whether parents of `commit` are missing because the repository is a shallow clone.
+    pub fn is_shallow_boundary(&self, commit: &CommitHash, history: HistoryMode) -> bool {
+        Oid::from_str(&commit.0)
+            .and_then(|oid| self.inner.find_commit(oid))
+            .map(|commit| {
+                self.shallow_roots().contains(&commit.id())
+                    || has_missing_parents(&commit, history)
+            })
+            .unwrap_or(false)
+    }
+
+    /// The commits listed in `.git/shallow`. libgit2 presents them as commits
+    /// without parents.
+    fn shallow_roots(&self) -> HashSet<Oid> {
+        if !self.inner.is_shallow() {
+            return HashSet::new();
+        }
+        std::fs::read_to_string(self.inner.path().join("shallow"))
+            .map(|content| {
+                content
+                    .lines()
+                    .filter_map(|line| Oid::from_str(line.trim()).ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn is_shallow(&self) -> bool {
+        self.inner.is_shallow()
+    }
+
+    /// The commit time of `commit` in seconds since the epoch.
+    pub fn commit_time(&self, commit: &CommitHash) -> Result<i64> {
+        let commit = Oid::from_str(&commit.0).expect("Couldn't parse commit hash");
+        Ok(self.inner.find_commit(commit)?.time().seconds())
+    }
+
+    /// Commits reachable from `start` that changed `file`, newest first,
+    /// together with their commit time in seconds since the epoch.
+    pub fn commits_changing(
+        &self,
+        start: &CommitHash,
+        file: &Path,
+    ) -> Result<Vec<(CommitHash, i64)>> {
+        let mut walk = self.inner.revwalk()?;
+        walk.push(Oid::from_str(&start.0)?)?;
+        walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+        let mut ret = Vec::new();
+        for oid in walk {
+            let commit = self.inner.find_commit(oid?)?;
+            let id = match commit.tree()?.get_path(file) {
+                Ok(entry) => entry.id(),
+                Err(_) => continue,
+            };
+            let unchanged = commit.parents().any(|parent| {
+                parent
+                    .tree()
+                    .ok()
+                    .and_then(|tree| tree.get_path(file).ok())
+                    .map(|entry| entry.id() == id)
+                    .unwrap_or(false)
+            });
+            if !unchanged {
+                ret.push((CommitHash(commit.id().to_string()), commit.time().seconds()));
+            }
+        }
+        Ok(ret)
     }
 
     pub fn find_last_changed_commit(
         &self,
         file: &Path,
         from_commit: CommitHash,
+        history: HistoryMode,
     ) -> Result<(CommitHash, String)> {
         let commit = Oid::from_str(&from_commit.0).expect("Couldn't parse commit hash");
         let commit = self.inner.find_commit(commit)?;
@@ -428,7 +796,7 @@ impl Repo {
         loop {
             let commit = queue.pop_front().unwrap();
             let mut go = false;
-            for parent in commit.parents() {
+            for parent in parents(&commit, history) {
                 if let Ok(tree) = parent.tree().expect("Couldn't get tree").get_path(file) {
                     let eq = tree.id() == target.id();
                     if eq && set.insert(parent.id()) {
@@ -446,6 +814,25 @@ impl Repo {
         }
     }
 
+    /// How `file` changed in `commit` compared to its parents.
+    pub fn file_change(
+        &self,
+        file: &Path,
+        commit: &CommitHash,
+        history: HistoryMode,
+    ) -> Result<FileChange> {
+        let commit = self.inner.find_commit(Oid::from_str(&commit.0)?)?;
+        if has_missing_parents(&commit, history) || commit.parent_count() == 0 {
+            return Ok(FileChange::FirstCommit);
+        }
+        for parent in parents(&commit, history) {
+            if parent.tree()?.get_path(file).is_ok() {
+                return Ok(FileChange::Modified);
+            }
+        }
+        Ok(FileChange::Added)
+    }
+
     pub fn get_file_content<F, T>(&self, commit: CommitHash, file: &Path, f: F) -> Result<Option<T>>
     where
         F: Fn(&[u8]) -> Result<T>,
@@ -484,12 +871,274 @@ impl Repo {
         self.gate_commit().id()
     }
 
-    fn gate_object(&self) -> Object {
+    fn gate_object(&self) -> Object<'_> {
         self.inner
             .find_object(self.gate_oid(), Some(ObjectType::Commit))
             .unwrap()
     }
 
+    /// True if `ancestor` is `commit` or one of its ancestors.
+    pub fn is_ancestor(&self, ancestor: &CommitHash, commit: &CommitHash) -> bool {
+        match (Oid::from_str(&ancestor.0), Oid::from_str(&commit.0)) {
+            (Ok(ancestor), Ok(commit)) => {
+                ancestor == commit
+                    || self
+                        .inner
+                        .graph_descendant_of(commit, ancestor)
+                        .unwrap_or(false)
+            }
+            _ => false,
+        }
+    }
+
+    /// The commit `refname` points to, if it exists.
+    pub fn ref_tip(&self, refname: &str) -> Option<CommitHash> {
+        self.inner
+            .find_reference(refname)
+            .and_then(|r| r.peel_to_commit())
+            .ok()
+            .map(|commit| CommitHash(commit.id().to_string()))
+    }
+
+    pub fn set_ref(&self, refname: &str, commit: &CommitHash, message: &str) -> Result<()> {
+        self.inner
+            .reference(refname, Oid::from_str(&commit.0)?, true, message)
+            .with_context(|| format!("Couldn't update '{}'", refname))?;
+        Ok(())
+    }
+
+    /// Names of the files directly inside `dir` at `commit`.
+    pub fn files_in_dir(&self, commit: &CommitHash, dir: &Path) -> Result<Vec<String>> {
+        let commit = self.inner.find_commit(Oid::from_str(&commit.0)?)?;
+        let tree = commit.tree().context("Couldn't resolve tree")?;
+        let entry = match tree.get_path(dir) {
+            Ok(entry) => entry,
+            Err(_) => return Ok(Vec::new()),
+        };
+        let object = entry.to_object(&self.inner)?;
+        let dir = match object.as_tree() {
+            Some(dir) => dir,
+            None => return Ok(Vec::new()),
+        };
+        Ok(dir
+            .iter()
+            .filter(|entry| entry.kind() == Some(ObjectType::Blob))
+            .filter_map(|entry| entry.name().map(|name| name.to_string()))
+            .collect())
+    }
+
+    /// Commits `content` to `path` on top of `refname` without touching the working tree.
+    /// Creates `refname` as an orphan if it doesn't exist yet.
+    pub fn commit_file_to_ref(
+        &self,
+        refname: &str,
+        path: &Path,
+        content: &[u8],
+        message: &str,
+    ) -> Result<CommitHash> {
+        let parent = self
+            .inner
+            .find_reference(refname)
+            .and_then(|r| r.peel_to_commit())
+            .ok();
+        let base = match parent.as_ref() {
+            Some(parent) => parent.tree()?,
+            None => {
+                let empty = self.inner.treebuilder(None)?.write()?;
+                self.inner.find_tree(empty)?
+            }
+        };
+        let blob = self.inner.blob(content)?;
+        let mut update = TreeUpdateBuilder::new();
+        update.upsert(path, blob, FileMode::Blob);
+        let tree = self
+            .inner
+            .find_tree(update.create_updated(&self.inner, &base)?)?;
+        let sig = Signature::now("Cepler", "bot@cepler.io")?;
+        let parents: Vec<_> = parent.iter().collect();
+        let oid = self
+            .inner
+            .commit(Some(refname), &sig, &sig, message, &tree, &parents)
+            .with_context(|| format!("Couldn't commit to '{}'", refname))?;
+        Ok(CommitHash(oid.to_string()))
+    }
+
+    pub fn read_note(&self, notes_ref: &str, commit: &CommitHash) -> Option<Vec<u8>> {
+        let oid = Oid::from_str(&commit.0).ok()?;
+        self.inner
+            .find_note(Some(notes_ref), oid)
+            .ok()
+            .map(|note| note.message_bytes().to_vec())
+    }
+
+    pub fn write_note(&self, notes_ref: &str, commit: &CommitHash, content: &str) -> Result<()> {
+        let sig = Signature::now("Cepler", "bot@cepler.io")?;
+        self.inner
+            .note(
+                &sig,
+                &sig,
+                Some(notes_ref),
+                Oid::from_str(&commit.0)?,
+                content,
+                true,
+            )
+            .with_context(|| format!("Couldn't write note to '{}'", notes_ref))?;
+        Ok(())
+    }
+
+    /// Resolves `<from>..<to>` to both commits and `<from>...<to>` to the merge base
+    /// and `<to>`. A single revision resolves to its first parent and itself.
+    pub fn resolve_range(&self, range: &str) -> Result<(Option<CommitHash>, CommitHash)> {
+        let invalid = || CeplerError::InvalidRevision(range.to_string());
+        let spec = self.inner.revparse(range).map_err(|_| invalid())?;
+        let commit = |object: Option<&Object>| -> Result<Commit> {
+            let id = object.ok_or_else(invalid)?.peel_to_commit()?.id();
+            Ok(self.inner.find_commit(id)?)
+        };
+        let to = commit(spec.to().or_else(|| spec.from()))?;
+        let from = if spec.mode().contains(git2::RevparseMode::SINGLE) {
+            to.parents().next()
+        } else {
+            Some(commit(spec.from())?)
+        };
+        let from = match from {
+            Some(from) if spec.mode().contains(git2::RevparseMode::MERGE_BASE) => Some(
+                self.inner
+                    .find_commit(self.inner.merge_base(from.id(), to.id())?)?,
+            ),
+            from => from,
+        };
+        Ok((
+            from.map(|c| CommitHash(c.id().to_string())),
+            CommitHash(to.id().to_string()),
+        ))
+    }
+
+    /// The paths that were added, changed or removed between `from` and `to`.
+    /// All files of `to` when there is no `from`.
+    pub fn changed_paths(&self, from: Option<&CommitHash>, to: &CommitHash) -> Result<Vec<String>> {
+        let tree = |commit: &CommitHash| -> Result<git2::Tree> {
+            Ok(self.inner.find_commit(Oid::from_str(&commit.0)?)?.tree()?)
+        };
+        let old_tree = from.map(tree).transpose()?;
+        let diff = self
+            .inner
+            .diff_tree_to_tree(old_tree.as_ref(), Some(&tree(to)?), None)?;
+        let mut paths = Vec::new();
+        for delta in diff.deltas() {
+            for file in [delta.old_file(), delta.new_file()] {
+                if file.id().is_zero() {
+                    continue;
+                }
+                if let Some(path) = file.path().and_then(|p| p.to_str()) {
+                    paths.push(path.to_string());
+                }
+            }
+        }
+        paths.sort();
+        paths.dedup();
+        Ok(paths)
+    }
+
+    /// Walks the history of `refname`, newest first, returning for each commit its time
+    /// and the content of every blob it added or changed compared to its first parent.
+    pub fn blobs_changed_on_ref(&self, refname: &str) -> Result<Vec<RefChange>> {
+        let tip = match self.ref_tip(refname) {
+            Some(tip) => tip,
+            None => return Ok(Vec::new()),
+        };
+        let mut walk = self.inner.revwalk()?;
+        walk.push(Oid::from_str(&tip.0)?)?;
+        walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+        let mut ret = Vec::new();
+        for oid in walk {
+            let commit = self.inner.find_commit(oid?)?;
+            let parent_tree = commit.parents().next().map(|p| p.tree()).transpose()?;
+            let diff =
+                self.inner
+                    .diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+            let mut blobs = Vec::new();
+            for delta in diff.deltas() {
+                let id = delta.new_file().id();
+                if id.is_zero() {
+                    continue;
+                }
+                blobs.push(self.inner.find_blob(id)?.content().to_vec());
+            }
+            ret.push((
+                CommitHash(commit.id().to_string()),
+                commit.time().seconds(),
+                blobs,
+            ));
+        }
+        Ok(ret)
+    }
+
+    /// Fetches `refname` from origin into `local_ref`, overwriting it.
+    /// Returns `false` if the remote doesn't have the ref.
+    pub fn fetch_ref(
+        &self,
+        credentials: &GitCredentials,
+        refname: &str,
+        local_ref: &str,
+    ) -> Result<bool> {
+        let mut remote = self.inner.find_remote("origin")?;
+        let url = remote.url().unwrap_or_default().to_string();
+        let (callbacks, host_key) = remote_callbacks(credentials.clone(), &url);
+        host_key.check(remote.connect_auth(git2::Direction::Fetch, Some(callbacks), None))?;
+        let exists = remote.list()?.iter().any(|head| head.name() == refname);
+        remote.disconnect()?;
+        if !exists {
+            return Ok(false);
+        }
+        let (callbacks, host_key) = remote_callbacks(credentials.clone(), &url);
+        let mut fo = git2::FetchOptions::new();
+        fo.remote_callbacks(callbacks);
+        host_key
+            .check(remote.fetch(
+                &[format!("+{}:{}", refname, local_ref)],
+                Some(&mut fo),
+                None,
+            ))
+            .with_context(|| format!("Couldn't fetch '{}'", refname))?;
+        Ok(true)
+    }
+
+    pub fn push_ref(&self, credentials: &GitCredentials, refname: &str) -> Result<()> {
+        self.push_refspec(credentials, &format!("{}:{}", refname, refname))
+    }
+
+    /// Pushes `refspec` to origin, failing with `CeplerError::PushRejected`
+    /// if the remote moved on in the meantime.
+    fn push_refspec(&self, credentials: &GitCredentials, refspec: &str) -> Result<()> {
+        let rejected = RefCell::new(None);
+        let mut remote = self.inner.find_remote("origin")?;
+        let (mut callbacks, host_key) =
+            remote_callbacks(credentials.clone(), remote.url().unwrap_or_default());
+        callbacks.push_update_reference(|refname, status| {
+            if let Some(status) = status {
+                *rejected.borrow_mut() = Some(format!("{}: {}", refname, status));
+            }
+            Ok(())
+        });
+        let mut push_options = PushOptions::new();
+        push_options.remote_callbacks(callbacks);
+        let res = host_key.check(remote.push(&[refspec], Some(&mut push_options)));
+        drop(push_options);
+        if let Err(e) = res {
+            return match e.downcast_ref::<git2::Error>() {
+                Some(e) if e.code() == git2::ErrorCode::NotFastForward => {
+                    Err(CeplerError::PushRejected(e.message().to_string()).into())
+                }
+                _ => Err(e.context("Couldn't push to remote")),
+            };
+        }
+        if let Some(reason) = rejected.into_inner() {
+            return Err(CeplerError::PushRejected(reason).into());
+        }
+        Ok(())
+    }
+
     pub fn get_file_from_branch<F, T>(&self, name: &str, file: &Path, f: F) -> Result<Option<T>>
     where
         F: Fn(&[u8]) -> Result<T>,
@@ -506,10 +1155,168 @@ impl Repo {
     }
 }
 
-fn remote_callbacks(key: String) -> RemoteCallbacks<'static> {
+/// The parents of `commit` to follow in `history` mode.
+fn parents<'a, 'b>(
+    commit: &'b Commit<'a>,
+    history: HistoryMode,
+) -> impl Iterator<Item = Commit<'a>> + 'b {
+    let follow = match history {
+        HistoryMode::All => usize::MAX,
+        HistoryMode::FirstParent => 1,
+    };
+    commit.parents().take(follow)
+}
+
+fn has_missing_parents(commit: &Commit, history: HistoryMode) -> bool {
+    let count = match history {
+        HistoryMode::All => commit.parent_count(),
+        HistoryMode::FirstParent => commit.parent_count().min(1),
+    };
+    (0..count).any(|i| commit.parent(i).is_err())
+}
+
+/// Callbacks authenticating to the remote at `url` and, for ssh remotes,
+/// verifying its host key according to `credentials.host_key_check`.
+fn remote_callbacks(
+    credentials: GitCredentials,
+    url: &str,
+) -> (RemoteCallbacks<'static>, HostKeyRejection) {
     let mut callbacks = RemoteCallbacks::new();
-    callbacks.credentials(move |_url, username_from_url, _allowed_types| {
-        Cred::ssh_key_from_memory(username_from_url.unwrap(), None, &key, None)
+    let rejection = HostKeyRejection::default();
+    if let Some(port) = ssh_port(url) {
+        let check = credentials.host_key_check.clone();
+        let reason = rejection.clone();
+        callbacks.certificate_check(move |cert, hostname| {
+            match check.verify(cert, hostname, port) {
+                Ok(()) => Ok(git2::CertificateCheckStatus::CertificateOk),
+                Err(e) => {
+                    let err = git2::Error::from_str(&e);
+                    *reason.0.borrow_mut() = Some(e);
+                    Err(err)
+                }
+            }
+        });
+    }
+    let mut attempts = 0;
+    callbacks.credentials(move |url, username_from_url, allowed_types| {
+        // libgit2 keeps asking as long as the server rejects what we hand out.
+        attempts += 1;
+        if attempts > MAX_CREDENTIAL_ATTEMPTS {
+            return Err(git2::Error::from_str(&format!(
+                "Authentication to '{}' failed",
+                url
+            )));
+        }
+        credentials.cred(url, username_from_url, allowed_types)
     });
-    callbacks
+    (callbacks, rejection)
+}
+
+/// Why the certificate check callback rejected the host key, if it did.
+/// libgit2 only reports that the check failed.
+#[derive(Clone, Default)]
+struct HostKeyRejection(Rc<RefCell<Option<String>>>);
+
+impl HostKeyRejection {
+    fn check<T>(&self, res: std::result::Result<T, git2::Error>) -> Result<T> {
+        match (res, self.0.borrow_mut().take()) {
+            (Err(_), Some(reason)) => Err(CeplerError::HostKeyRejected(reason).into()),
+            (res, _) => Ok(res?),
+        }
+    }
+}
+
+impl HostKeyCheck {
+    fn verify(&self, cert: &Cert, host: &str, port: u16) -> std::result::Result<(), String> {
+        let known_hosts = match self {
+            HostKeyCheck::Insecure => return Ok(()),
+            HostKeyCheck::KnownHosts(content) => content.clone(),
+            HostKeyCheck::UserKnownHosts => std::env::var("HOME")
+                .ok()
+                .and_then(|home| {
+                    std::fs::read_to_string(Path::new(&home).join(".ssh/known_hosts")).ok()
+                })
+                .unwrap_or_default(),
+        };
+        let known_hosts = KnownHosts::parse(&known_hosts)
+            .map_err(|e| format!("Couldn't parse known_hosts: {}", e))?;
+        let hostkey = cert
+            .as_hostkey()
+            .ok_or_else(|| format!("'{}' didn't present an ssh host key", host))?;
+        let key = match (hostkey.hash_sha256(), hostkey.hash_sha1()) {
+            (Some(hash), _) => HostKey::Sha256(hash),
+            (None, Some(hash)) => HostKey::Sha1(hash),
+            _ => return Err(format!("No fingerprint of the host key of '{}'", host)),
+        };
+        match known_hosts.check(host, port, key) {
+            HostKeyStatus::Match => Ok(()),
+            HostKeyStatus::Mismatch => Err(format!(
+                "The host key of '{}' doesn't match the one in known_hosts",
+                host
+            )),
+            HostKeyStatus::Revoked => Err(format!("The host key of '{}' was revoked", host)),
+            HostKeyStatus::Unknown => Err(format!("'{}' is not in known_hosts", host)),
+        }
+    }
+}
+
+const MAX_CREDENTIAL_ATTEMPTS: usize = 3;
+
+impl GitCredentials {
+    fn cred(
+        &self,
+        url: &str,
+        username_from_url: Option<&str>,
+        allowed_types: CredentialType,
+    ) -> std::result::Result<Cred, git2::Error> {
+        let username = self.username.as_deref().or(username_from_url);
+        if allowed_types.contains(CredentialType::SSH_KEY) {
+            let username = username.unwrap_or("git");
+            let passphrase = self.passphrase.as_deref();
+            if let Some(key) = &self.private_key {
+                return Cred::ssh_key_from_memory(username, None, key, passphrase);
+            }
+            if let Some(path) = &self.private_key_path {
+                return Cred::ssh_key(username, None, path, passphrase);
+            }
+            return Cred::ssh_key_from_agent(username);
+        }
+        if allowed_types.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            if let Some(password) = &self.password {
+                return Cred::userpass_plaintext(username.unwrap_or(TOKEN_USERNAME), password);
+            }
+            let config = git2::Config::open_default()?;
+            return Cred::credential_helper(&config, url, username);
+        }
+        if allowed_types.contains(CredentialType::USERNAME) {
+            return Cred::username(username.unwrap_or("git"));
+        }
+        Err(git2::Error::from_str(&format!(
+            "No credentials configured for '{}'",
+            url
+        )))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pathspec_prefixes() {
+        let pathspec = Pathspec::from_patterns([
+            "deploy/staging/*.yml",
+            "deploy/staging/values/**/*.yml",
+            "charts/app/Chart.yaml",
+            "charts/*/values.yml",
+        ]);
+        assert_eq!(
+            pathspec.prefixes,
+            vec![PathBuf::from("charts"), PathBuf::from("deploy/staging")]
+        );
+        assert_eq!(
+            Pathspec::from_patterns(["**/*.yml", "deploy/file.yml"]),
+            Pathspec::all()
+        );
+    }
 }
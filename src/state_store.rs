@@ -0,0 +1,789 @@
+use super::{
+    config::StateStoreKind,
+    database::EnvironmentState,
+    error::CeplerError,
+    repo::{CommitHash, GitConfig, Repo},
+};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+pub const DEFAULT_STATE_BRANCH: &str = "cepler-state";
+pub const DEFAULT_NOTES_REF: &str = "refs/notes/cepler";
+
+/// A version of an environment together with the commit that persisted it
+/// and the commit time in seconds since the epoch.
+pub type RecordedState = (CommitHash, i64, EnvironmentState);
+
+/// Where the `Database` keeps the state of the environments.
+///
+/// All paths are the `state_dir` of the `Database` joined with `<env>.state`
+/// so that different config files and scopes never share state.
+pub trait StateStore {
+    fn kind(&self) -> StateStoreKind;
+
+    /// The current state of every environment in `state_dir`.
+    fn load(&self, repo: &Repo, state_dir: &str) -> Result<HashMap<String, EnvironmentState>>;
+
+    /// The state of `env` as seen from `commit` of the deployed branch.
+    fn load_at_commit(
+        &self,
+        repo: &Repo,
+        state_dir: &str,
+        env: &str,
+        commit: &CommitHash,
+    ) -> Result<Option<EnvironmentState>>;
+
+    /// Every persisted version of `env`, newest first.
+    fn history(&self, repo: &Repo, state_dir: &str, env: &str) -> Result<Vec<RecordedState>>;
+
+    /// Persists the state of `env`. Returns where it was written to.
+    fn persist(
+        &self,
+        repo: &Repo,
+        state_dir: &str,
+        scope: &str,
+        env: &str,
+        state: &EnvironmentState,
+        commit: bool,
+    ) -> Result<String>;
+
+    /// Publishes the persisted state to the remote. Returns `false` if there was nothing to push.
+    fn push(
+        &self,
+        repo: &Repo,
+        config: GitConfig,
+        state_dir: &str,
+        scope: &str,
+        env: &str,
+        state: &EnvironmentState,
+    ) -> Result<bool>;
+
+    /// Updates the local copy of the state from the remote.
+    fn fetch(&self, repo: &Repo, config: &GitConfig) -> Result<()>;
+}
+
+pub fn state_store(kind: StateStoreKind, state_ref: Option<&str>) -> Rc<dyn StateStore> {
+    match kind {
+        StateStoreKind::Tree => Rc::new(TreeStore),
+        StateStoreKind::Branch => {
+            Rc::new(BranchStore::new(state_ref.unwrap_or(DEFAULT_STATE_BRANCH)))
+        }
+        StateStoreKind::Notes => Rc::new(NotesStore::new(state_ref.unwrap_or(DEFAULT_NOTES_REF))),
+    }
+}
+
+fn state_file(state_dir: &str, env: &str) -> String {
+    format!("{}/{}.state", state_dir, env)
+}
+
+/// Exclusive lock on a state dir held while an environment is being persisted.
+/// The lock file lives in the git dir so it can never be committed by accident
+/// and is removed again when the lock is dropped. A lock left behind by a process
+/// that is no longer running is taken over.
+pub struct StateLock {
+    path: PathBuf,
+}
+
+impl StateLock {
+    pub fn acquire(repo: &Repo, state_dir: &str) -> Result<Self> {
+        let path = repo
+            .git_dir()
+            .join(format!("cepler-{}.lock", state_dir.replace('/', "-")));
+        match Self::create(&path) {
+            Err(e) if is_locked(&e) && Self::remove_if_stale(&path) => Self::create(&path),
+            res => res,
+        }
+    }
+
+    fn create(path: &Path) -> Result<Self> {
+        match OpenOptions::new().write(true).create_new(true).open(path) {
+            Ok(mut file) => {
+                writeln!(file, "{}", std::process::id())?;
+                Ok(Self {
+                    path: path.to_path_buf(),
+                })
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                Err(CeplerError::StateLocked(path.to_string_lossy().to_string()).into())
+            }
+            Err(e) => {
+                Err(e).with_context(|| format!("Couldn't create lock file '{}'", path.display()))
+            }
+        }
+    }
+
+    /// Removes the lock file if the process that wrote it isn't running anymore.
+    fn remove_if_stale(path: &Path) -> bool {
+        let pid = match fs::read_to_string(path)
+            .ok()
+            .and_then(|content| content.trim().parse::<u32>().ok())
+        {
+            Some(pid) => pid,
+            None => return false,
+        };
+        if process_is_running(pid) {
+            return false;
+        }
+        // Only remove the file if it still names the dead process so that a lock
+        // taken over by someone else in the meantime stays in place.
+        fs::read_to_string(path)
+            .map(|content| content.trim() == pid.to_string())
+            .unwrap_or(false)
+            && fs::remove_file(path).is_ok()
+    }
+}
+
+fn is_locked(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<CeplerError>(), Some(CeplerError::StateLocked(_)))
+}
+
+#[cfg(unix)]
+fn process_is_running(pid: u32) -> bool {
+    // Signal 0 only checks whether the process exists. EPERM means it exists
+    // but belongs to another user.
+    let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    res == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_is_running(_pid: u32) -> bool {
+    true
+}
+
+impl Drop for StateLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Writes `content` to a temporary file next to `path` and renames it into place
+/// so that readers (and `git add`) never see a partially written file.
+fn write_atomically(path: &Path, content: &[u8]) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let tmp = path.with_file_name(format!(".{}.tmp", file_name));
+    let res = File::create(&tmp)
+        .and_then(|mut file| {
+            file.write_all(content)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, path));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res.with_context(|| format!("Couldn't write '{}'", path.display()))
+}
+
+/// Fails if `env` was recorded by someone else since `state` was loaded,
+/// ie. if the remote isn't at the version `state` was derived from.
+fn ensure_not_recorded_concurrently(
+    env: &str,
+    state: &EnvironmentState,
+    remote: Option<&EnvironmentState>,
+) -> Result<()> {
+    let expected = state
+        .version()
+        .checked_sub(1)
+        .ok_or_else(|| anyhow!("State of '{}' has the invalid version 0", env))?;
+    let found = remote.map(|s| s.version()).unwrap_or(0);
+    if found != expected {
+        return Err(CeplerError::ConcurrentRecord(env.to_string(), expected, found).into());
+    }
+    Ok(())
+}
+
+/// The newest state of every environment that was recorded locally but isn't on the remote
+/// yet. `local` and `remote` hold every recorded version of each environment.
+/// Fails if the remote recorded one of them in the meantime, as replaying would drop its version.
+fn unpushed_states(
+    local: BTreeMap<String, Vec<EnvironmentState>>,
+    remote: &BTreeMap<String, Vec<EnvironmentState>>,
+) -> Result<Vec<(String, EnvironmentState)>> {
+    let mut ret = Vec::new();
+    for (env, local) in local {
+        let remote = remote.get(&env).map(Vec::as_slice).unwrap_or_default();
+        let newest = match local.iter().max_by_key(|s| s.version()) {
+            Some(newest) => newest,
+            None => continue,
+        };
+        let remote_version = remote.iter().map(|s| s.version()).max().unwrap_or(0);
+        // Both sides have to agree on the newest version they have in common.
+        let shared = newest.version().min(remote_version);
+        if shared > 0 {
+            let find = |states: &[EnvironmentState]| {
+                states
+                    .iter()
+                    .find(|s| s.version() == shared)
+                    .map(to_yaml)
+                    .transpose()
+            };
+            let local_shared = find(&local)?;
+            if local_shared.is_none() || local_shared != find(remote)? {
+                return Err(CeplerError::ConcurrentRecord(
+                    env,
+                    newest.version().saturating_sub(1),
+                    remote_version,
+                )
+                .into());
+            }
+        }
+        if newest.version() > remote_version {
+            ret.push((env, newest.clone()));
+        }
+    }
+    Ok(ret)
+}
+
+/// The tip of `remote_ref` right after `Repo::fetch_ref` reported it was fetched.
+fn fetched_tip(repo: &Repo, remote_ref: &str) -> Result<CommitHash> {
+    repo.ref_tip(remote_ref)
+        .with_context(|| format!("Couldn't find '{}' after fetching it", remote_ref))
+}
+
+fn to_yaml(state: &EnvironmentState) -> Result<Vec<u8>> {
+    let mut bytes = serde_yaml::to_vec(state)?;
+    bytes.extend("\n".as_bytes());
+    Ok(bytes)
+}
+
+fn record_message(scope: &str, env: &str) -> String {
+    if scope != super::config::default_scope() {
+        format!("ci(cepler): Updated '{}' state in '{}'", scope, env)
+    } else {
+        format!("ci(cepler): Updated '{}' state", env)
+    }
+}
+
+/// The newest version of `env` whose head commit is `commit` or one of its ancestors.
+fn visible_at(
+    repo: &Repo,
+    history: &[RecordedState],
+    commit: &CommitHash,
+) -> Option<EnvironmentState> {
+    history
+        .iter()
+        .find(|(_, _, state)| repo.is_ancestor(&state.current().head_commit, commit))
+        .map(|(_, _, state)| state.clone())
+}
+
+/// Keeps the state files in the working tree and commits them onto the deployed branch.
+pub struct TreeStore;
+
+impl StateStore for TreeStore {
+    fn kind(&self) -> StateStoreKind {
+        StateStoreKind::Tree
+    }
+
+    fn load(&self, repo: &Repo, state_dir: &str) -> Result<HashMap<String, EnvironmentState>> {
+        let mut ret = HashMap::new();
+        let full_dir = repo.workdir().join(state_dir);
+        if full_dir.is_dir() {
+            let pattern = format!(
+                "{}/*.state",
+                glob::Pattern::escape(&full_dir.to_string_lossy())
+            );
+            for path in glob::glob(&pattern)? {
+                let path = path?;
+                if let Some(name) = path.as_path().file_stem() {
+                    let file = File::open(&path)?;
+                    ret.insert(
+                        name.to_str().expect("Convert name").to_string(),
+                        EnvironmentState::from_reader(BufReader::new(file))?,
+                    );
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    fn load_at_commit(
+        &self,
+        repo: &Repo,
+        state_dir: &str,
+        env: &str,
+        commit: &CommitHash,
+    ) -> Result<Option<EnvironmentState>> {
+        repo.get_file_content(
+            commit.clone(),
+            Path::new(&state_file(state_dir, env)),
+            |bytes| EnvironmentState::from_reader(bytes),
+        )
+    }
+
+    fn history(&self, repo: &Repo, state_dir: &str, env: &str) -> Result<Vec<RecordedState>> {
+        let path = state_file(state_dir, env);
+        let mut ret = Vec::new();
+        for (commit, time) in repo.commits_changing(&repo.head_commit_hash()?, Path::new(&path))? {
+            if let Some(state) =
+                repo.get_file_content(commit.clone(), Path::new(&path), |bytes| {
+                    EnvironmentState::from_reader(bytes)
+                })?
+            {
+                ret.push((commit, time, state));
+            }
+        }
+        Ok(ret)
+    }
+
+    fn persist(
+        &self,
+        repo: &Repo,
+        state_dir: &str,
+        scope: &str,
+        env: &str,
+        state: &EnvironmentState,
+        commit: bool,
+    ) -> Result<String> {
+        let dir = repo.workdir().join(state_dir);
+        fs::create_dir_all(&dir)?;
+        write_atomically(&dir.join(format!("{}.state", env)), &to_yaml(state)?)?;
+        let path = state_file(state_dir, env);
+        if commit {
+            repo.commit_state_file(scope, path.clone())?;
+        }
+        Ok(path)
+    }
+
+    fn push(
+        &self,
+        repo: &Repo,
+        config: GitConfig,
+        state_dir: &str,
+        _: &str,
+        env: &str,
+        state: &EnvironmentState,
+    ) -> Result<bool> {
+        let remote = repo.fetch_branch(&config)?;
+        let remote_state =
+            repo.get_file_content(remote, Path::new(&state_file(state_dir, env)), |bytes| {
+                EnvironmentState::from_reader(bytes)
+            })?;
+        ensure_not_recorded_concurrently(env, state, remote_state.as_ref())?;
+        repo.push(config)
+    }
+
+    fn fetch(&self, _: &Repo, _: &GitConfig) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps the state files on a dedicated orphan branch so the deployed branch
+/// never receives `ci(cepler)` commits.
+pub struct BranchStore {
+    refname: String,
+    remote_ref: String,
+    history: RefCell<BTreeMap<String, Vec<RecordedState>>>,
+}
+
+impl BranchStore {
+    fn new(branch: &str) -> Self {
+        let branch = branch.trim_start_matches("refs/heads/");
+        Self {
+            refname: format!("refs/heads/{}", branch),
+            remote_ref: format!("refs/remotes/origin/{}", branch),
+            history: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    fn tip(&self, repo: &Repo) -> Option<CommitHash> {
+        repo.ref_tip(&self.refname)
+            .or_else(|| repo.ref_tip(&self.remote_ref))
+    }
+
+    /// Every recorded version of each environment with a state file at `tip`.
+    fn states_at(
+        &self,
+        repo: &Repo,
+        tip: &CommitHash,
+        state_dir: &str,
+    ) -> Result<BTreeMap<String, Vec<EnvironmentState>>> {
+        let mut ret = BTreeMap::new();
+        for name in repo.files_in_dir(tip, Path::new(state_dir))? {
+            if let Some(env) = name.strip_suffix(".state") {
+                let path = state_file(state_dir, env);
+                let mut states = Vec::new();
+                for (commit, _) in repo.commits_changing(tip, Path::new(&path))? {
+                    if let Some(state) = repo.get_file_content(commit, Path::new(&path), |bytes| {
+                        EnvironmentState::from_reader(bytes)
+                    })? {
+                        states.push(state);
+                    }
+                }
+                ret.insert(env.to_string(), states);
+            }
+        }
+        Ok(ret)
+    }
+
+    fn write(
+        &self,
+        repo: &Repo,
+        state_dir: &str,
+        scope: &str,
+        env: &str,
+        state: &EnvironmentState,
+    ) -> Result<()> {
+        if repo.ref_tip(&self.refname).is_none() {
+            if let Some(remote) = repo.ref_tip(&self.remote_ref) {
+                repo.set_ref(&self.refname, &remote, "cepler: track remote state branch")?;
+            }
+        }
+        repo.commit_file_to_ref(
+            &self.refname,
+            Path::new(&state_file(state_dir, env)),
+            &to_yaml(state)?,
+            &record_message(scope, env),
+        )?;
+        self.history.borrow_mut().clear();
+        Ok(())
+    }
+}
+
+impl StateStore for BranchStore {
+    fn kind(&self) -> StateStoreKind {
+        StateStoreKind::Branch
+    }
+
+    fn load(&self, repo: &Repo, state_dir: &str) -> Result<HashMap<String, EnvironmentState>> {
+        let mut ret = HashMap::new();
+        let tip = match self.tip(repo) {
+            Some(tip) => tip,
+            None => return Ok(ret),
+        };
+        for name in repo.files_in_dir(&tip, Path::new(state_dir))? {
+            if let Some(env) = name.strip_suffix(".state") {
+                if let Some(state) = repo.get_file_content(
+                    tip.clone(),
+                    Path::new(&state_file(state_dir, env)),
+                    |bytes| EnvironmentState::from_reader(bytes),
+                )? {
+                    ret.insert(env.to_string(), state);
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    fn load_at_commit(
+        &self,
+        repo: &Repo,
+        state_dir: &str,
+        env: &str,
+        commit: &CommitHash,
+    ) -> Result<Option<EnvironmentState>> {
+        Ok(visible_at(
+            repo,
+            &self.history(repo, state_dir, env)?,
+            commit,
+        ))
+    }
+
+    fn history(&self, repo: &Repo, state_dir: &str, env: &str) -> Result<Vec<RecordedState>> {
+        let path = state_file(state_dir, env);
+        if let Some(history) = self.history.borrow().get(&path) {
+            return Ok(history.clone());
+        }
+        let mut ret = Vec::new();
+        if let Some(tip) = self.tip(repo) {
+            for (commit, time) in repo.commits_changing(&tip, Path::new(&path))? {
+                if let Some(state) =
+                    repo.get_file_content(commit.clone(), Path::new(&path), |bytes| {
+                        EnvironmentState::from_reader(bytes)
+                    })?
+                {
+                    ret.push((commit, time, state));
+                }
+            }
+        }
+        self.history.borrow_mut().insert(path, ret.clone());
+        Ok(ret)
+    }
+
+    fn persist(
+        &self,
+        repo: &Repo,
+        state_dir: &str,
+        scope: &str,
+        env: &str,
+        state: &EnvironmentState,
+        commit: bool,
+    ) -> Result<String> {
+        if !commit {
+            return Err(anyhow!(
+                "The 'branch' state store always commits the recorded state"
+            ));
+        }
+        self.write(repo, state_dir, scope, env, state)?;
+        Ok(format!(
+            "{}:{}",
+            self.refname.trim_start_matches("refs/heads/"),
+            state_file(state_dir, env)
+        ))
+    }
+
+    fn push(
+        &self,
+        repo: &Repo,
+        config: GitConfig,
+        state_dir: &str,
+        scope: &str,
+        env: &str,
+        state: &EnvironmentState,
+    ) -> Result<bool> {
+        if repo.fetch_ref(&config.credentials, &self.refname, &self.remote_ref)? {
+            let remote = fetched_tip(repo, &self.remote_ref)?;
+            let local = repo.ref_tip(&self.refname).context("Nothing recorded")?;
+            if local == remote {
+                return Ok(false);
+            }
+            let remote_state = repo.get_file_content(
+                remote.clone(),
+                Path::new(&state_file(state_dir, env)),
+                |bytes| EnvironmentState::from_reader(bytes),
+            )?;
+            ensure_not_recorded_concurrently(env, state, remote_state.as_ref())?;
+            if !repo.is_ancestor(&remote, &local) {
+                // Someone else recorded in the meantime. Replay every state that
+                // was recorded locally on top of theirs.
+                let unpushed = unpushed_states(
+                    self.states_at(repo, &local, state_dir)?,
+                    &self.states_at(repo, &remote, state_dir)?,
+                )?;
+                repo.set_ref(
+                    &self.refname,
+                    &remote,
+                    "cepler: reset to remote state branch",
+                )?;
+                for (env, state) in unpushed {
+                    self.write(repo, state_dir, scope, &env, &state)?;
+                }
+            }
+        }
+        repo.push_ref(&config.credentials, &self.refname)?;
+        Ok(true)
+    }
+
+    fn fetch(&self, repo: &Repo, config: &GitConfig) -> Result<()> {
+        if repo.fetch_ref(&config.credentials, &self.refname, &self.remote_ref)? {
+            let remote = fetched_tip(repo, &self.remote_ref)?;
+            match repo.ref_tip(&self.refname) {
+                Some(local) if !repo.is_ancestor(&local, &remote) => (),
+                _ => repo.set_ref(&self.refname, &remote, "cepler: fast-forward state branch")?,
+            }
+        }
+        self.history.borrow_mut().clear();
+        Ok(())
+    }
+}
+
+/// Attaches the state as git notes to the head commit it was recorded for.
+/// Each note holds a map from state file path to the state of the environment.
+pub struct NotesStore {
+    notes_ref: String,
+    remote_ref: String,
+    history: RefCell<BTreeMap<String, Vec<RecordedState>>>,
+}
+
+impl NotesStore {
+    fn new(notes_ref: &str) -> Self {
+        let notes_ref = if notes_ref.starts_with("refs/") {
+            notes_ref.to_string()
+        } else {
+            format!("refs/notes/{}", notes_ref)
+        };
+        Self {
+            remote_ref: format!(
+                "refs/notes/remotes/origin/{}",
+                notes_ref.trim_start_matches("refs/notes/")
+            ),
+            notes_ref,
+            history: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    fn parse(bytes: &[u8]) -> Result<BTreeMap<String, EnvironmentState>> {
+        serde_yaml::from_slice(bytes).context("Couldn't parse state note")
+    }
+
+    /// Every distinct version of the state file at `path` found in the notes on `refname`.
+    fn states_on_ref(&self, repo: &Repo, refname: &str, path: &str) -> Result<Vec<RecordedState>> {
+        let mut ret: Vec<RecordedState> = Vec::new();
+        for (commit, time, blobs) in repo.blobs_changed_on_ref(refname)? {
+            for blob in blobs {
+                if let Some(state) = Self::parse(&blob)?.remove(path) {
+                    if !ret.iter().any(|(_, _, s)| s.version() == state.version()) {
+                        ret.push((commit.clone(), time, state));
+                    }
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Every distinct version of each environment found in the notes on `refname`.
+    fn states_by_env_on_ref(
+        &self,
+        repo: &Repo,
+        refname: &str,
+        state_dir: &str,
+    ) -> Result<BTreeMap<String, Vec<EnvironmentState>>> {
+        let mut ret: BTreeMap<String, Vec<EnvironmentState>> = BTreeMap::new();
+        let prefix = format!("{}/", state_dir);
+        for (_, _, blobs) in repo.blobs_changed_on_ref(refname)? {
+            for blob in blobs {
+                for (path, state) in Self::parse(&blob)? {
+                    let env = match path
+                        .strip_prefix(&prefix)
+                        .and_then(|name| name.strip_suffix(".state"))
+                    {
+                        Some(env) => env.to_string(),
+                        None => continue,
+                    };
+                    let states = ret.entry(env).or_default();
+                    if !states.iter().any(|s| s.version() == state.version()) {
+                        states.push(state);
+                    }
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    fn write(
+        &self,
+        repo: &Repo,
+        state_dir: &str,
+        env: &str,
+        state: &EnvironmentState,
+    ) -> Result<()> {
+        let head = &state.current().head_commit;
+        let mut states = match repo.read_note(&self.notes_ref, head) {
+            Some(bytes) => Self::parse(&bytes)?,
+            None => BTreeMap::new(),
+        };
+        states.insert(state_file(state_dir, env), state.clone());
+        repo.write_note(&self.notes_ref, head, &serde_yaml::to_string(&states)?)?;
+        self.history.borrow_mut().clear();
+        Ok(())
+    }
+}
+
+impl StateStore for NotesStore {
+    fn kind(&self) -> StateStoreKind {
+        StateStoreKind::Notes
+    }
+
+    fn load(&self, repo: &Repo, state_dir: &str) -> Result<HashMap<String, EnvironmentState>> {
+        Ok(self
+            .states_by_env_on_ref(repo, &self.notes_ref, state_dir)?
+            .into_iter()
+            .filter_map(|(env, states)| {
+                states
+                    .into_iter()
+                    .max_by_key(|s| s.version())
+                    .map(|state| (env, state))
+            })
+            .collect())
+    }
+
+    fn load_at_commit(
+        &self,
+        repo: &Repo,
+        state_dir: &str,
+        env: &str,
+        commit: &CommitHash,
+    ) -> Result<Option<EnvironmentState>> {
+        Ok(visible_at(
+            repo,
+            &self.history(repo, state_dir, env)?,
+            commit,
+        ))
+    }
+
+    fn history(&self, repo: &Repo, state_dir: &str, env: &str) -> Result<Vec<RecordedState>> {
+        let path = state_file(state_dir, env);
+        if let Some(history) = self.history.borrow().get(&path) {
+            return Ok(history.clone());
+        }
+        let mut ret = self.states_on_ref(repo, &self.notes_ref, &path)?;
+        ret.sort_by_key(|(_, _, state)| std::cmp::Reverse(state.version()));
+        self.history.borrow_mut().insert(path, ret.clone());
+        Ok(ret)
+    }
+
+    fn persist(
+        &self,
+        repo: &Repo,
+        state_dir: &str,
+        _: &str,
+        env: &str,
+        state: &EnvironmentState,
+        commit: bool,
+    ) -> Result<String> {
+        if !commit {
+            return Err(anyhow!(
+                "The 'notes' state store always commits the recorded state"
+            ));
+        }
+        self.write(repo, state_dir, env, state)?;
+        Ok(self.notes_ref.clone())
+    }
+
+    fn push(
+        &self,
+        repo: &Repo,
+        config: GitConfig,
+        state_dir: &str,
+        _: &str,
+        env: &str,
+        state: &EnvironmentState,
+    ) -> Result<bool> {
+        if repo.fetch_ref(&config.credentials, &self.notes_ref, &self.remote_ref)? {
+            let remote = fetched_tip(repo, &self.remote_ref)?;
+            let local = repo.ref_tip(&self.notes_ref).context("Nothing recorded")?;
+            if local == remote {
+                return Ok(false);
+            }
+            let remote_state = self
+                .states_on_ref(repo, &self.remote_ref, &state_file(state_dir, env))?
+                .into_iter()
+                .max_by_key(|(_, _, s)| s.version());
+            ensure_not_recorded_concurrently(env, state, remote_state.as_ref().map(|(_, _, s)| s))?;
+            if !repo.is_ancestor(&remote, &local) {
+                // Someone else recorded in the meantime. Replay every note that
+                // was written locally on top of theirs.
+                let unpushed = unpushed_states(
+                    self.states_by_env_on_ref(repo, &self.notes_ref, state_dir)?,
+                    &self.states_by_env_on_ref(repo, &self.remote_ref, state_dir)?,
+                )?;
+                repo.set_ref(&self.notes_ref, &remote, "cepler: reset to remote notes")?;
+                for (env, state) in unpushed {
+                    self.write(repo, state_dir, &env, &state)?;
+                }
+            }
+        }
+        repo.push_ref(&config.credentials, &self.notes_ref)?;
+        Ok(true)
+    }
+
+    fn fetch(&self, repo: &Repo, config: &GitConfig) -> Result<()> {
+        if repo.fetch_ref(&config.credentials, &self.notes_ref, &self.remote_ref)? {
+            let remote = fetched_tip(repo, &self.remote_ref)?;
+            match repo.ref_tip(&self.notes_ref) {
+                Some(local) if !repo.is_ancestor(&local, &remote) => (),
+                _ => repo.set_ref(&self.notes_ref, &remote, "cepler: fast-forward notes")?,
+            }
+        }
+        self.history.borrow_mut().clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,392 @@
+use super::{
+    config::{Config, EnvironmentConfig, MATCH_OPTIONS},
+    error::{self, CeplerError},
+    repo::{Pathspec, Repo},
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Serialize)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "{}:{}: {}", self.file, line, self.message)
+        } else {
+            write!(f, "{}: {}", self.file, self.message)
+        }
+    }
+}
+
+/// Lints the config file against itself and the files present at HEAD of the repository.
+/// Returns every problem found, ordered by line.
+pub fn validate(path_to_config: &str, repo_path: Option<&Path>) -> error::Result<Vec<Diagnostic>> {
+    let repo = Repo::open(repo_path, None)?;
+    let content =
+        std::fs::read_to_string(repo.workdir().join(path_to_config)).map_err(|source| {
+            CeplerError::Io {
+                message: "Couldn't open config file".to_string(),
+                source,
+            }
+        })?;
+    let config = match Config::from_reader_unchecked(content.as_bytes()) {
+        Ok(config) => config,
+        Err(CeplerError::Yaml { source, .. }) => {
+            return Ok(vec![Diagnostic {
+                file: path_to_config.to_string(),
+                line: source.location().map(|l| l.line()),
+                message: format!("Couldn't parse config: {}", source),
+            }]);
+        }
+        Err(e) => return Err(e),
+    };
+    let mut files = Vec::new();
+    repo.all_files(repo.gate_commit_hash(), &Pathspec::all(), |_, path| {
+        files.push(path.to_path_buf());
+        Ok(())
+    })?;
+    Ok(lint(path_to_config, &content, &config, &files))
+}
+
+fn lint(
+    path_to_config: &str,
+    content: &str,
+    config: &Config,
+    files: &[PathBuf],
+) -> Vec<Diagnostic> {
+    let source = Source::new(content);
+    let mut diagnostics = Vec::new();
+    let mut report = |line: Option<usize>, message: String| {
+        diagnostics.push(Diagnostic {
+            file: path_to_config.to_string(),
+            line,
+            message,
+        })
+    };
+    let mut names: Vec<_> = config.environments.keys().collect();
+    names.sort();
+    for name in names.iter() {
+        let env = &config.environments[name.as_str()];
+        for previous in env.propagated_from() {
+            let line = source.line_of(name, "passed", Some(previous));
+            if previous == *name {
+                report(line, format!("Environment '{}' cannot pass itself", name));
+            } else if !config.environments.contains_key(previous) {
+                report(
+                    line,
+                    format!(
+                        "Environment '{}' passes undefined environment '{}'",
+                        name, previous
+                    ),
+                );
+            }
+        }
+        let mut propagated = Vec::new();
+        for upstream in env.propagated_from() {
+            for pattern in env.propagated_files_from(upstream) {
+                if !propagated.contains(&pattern) {
+                    propagated.push(pattern);
+                }
+            }
+        }
+        for pattern in env.propagated_files() {
+            if !propagated.contains(&pattern) {
+                report(
+                    source.line_of(name, "propagated", Some(pattern)),
+                    format!(
+                        "Environment '{}' propagates '{}' but no matching environment is listed in 'passed'",
+                        name, pattern
+                    ),
+                );
+            }
+        }
+
+        let head = compile(&source, &mut report, env, "latest", env.head_files().iter());
+        let propagated = compile(
+            &source,
+            &mut report,
+            env,
+            "propagated",
+            propagated.into_iter(),
+        );
+        for (key, pattern, glob) in head.iter().chain(propagated.iter()) {
+            if !files
+                .iter()
+                .any(|f| glob.matches_path_with(f, MATCH_OPTIONS))
+            {
+                report(
+                    source.line_of(name, key, Some(pattern)),
+                    format!(
+                        "Environment '{}' pattern '{}' doesn't match any file at HEAD",
+                        name, pattern
+                    ),
+                );
+            }
+        }
+        for (_, head_pattern, head_glob) in head.iter() {
+            for (_, propagated_pattern, propagated_glob) in propagated.iter() {
+                if let Some(file) = files.iter().find(|f| {
+                    head_glob.matches_path_with(f, MATCH_OPTIONS)
+                        && propagated_glob.matches_path_with(f, MATCH_OPTIONS)
+                }) {
+                    report(
+                        source.line_of(name, "latest", Some(head_pattern)),
+                        format!(
+                            "Environment '{}' latest pattern '{}' shadows propagated pattern '{}' (e.g. '{}')",
+                            name,
+                            head_pattern,
+                            propagated_pattern,
+                            file.display()
+                        ),
+                    );
+                }
+            }
+        }
+    }
+    for cycle in find_cycles(config) {
+        report(
+            source.line_of(&cycle[0], "passed", None),
+            format!("Cycle in 'passed' chain: {}", cycle.join(" -> ")),
+        );
+    }
+    diagnostics.sort_by_key(|d| d.line);
+    diagnostics
+}
+
+fn compile<'a>(
+    source: &Source,
+    report: &mut impl FnMut(Option<usize>, String),
+    env: &EnvironmentConfig,
+    key: &'static str,
+    patterns: impl Iterator<Item = &'a String>,
+) -> Vec<(&'static str, &'a String, glob::Pattern)> {
+    let mut ret = Vec::new();
+    for pattern in patterns {
+        match glob::Pattern::new(pattern) {
+            Ok(glob) => ret.push((key, pattern, glob)),
+            Err(e) => report(
+                source.line_of(&env.name, key, Some(pattern)),
+                format!(
+                    "Environment '{}' has malformed glob pattern '{}': {}",
+                    env.name, pattern, e
+                ),
+            ),
+        }
+    }
+    ret
+}
+
+/// Returns every cycle in the `passed` graph once, starting at its
+/// alphabetically first environment and ending where it started.
+fn find_cycles(config: &Config) -> Vec<Vec<String>> {
+    fn visit(
+        config: &Config,
+        name: &str,
+        path: &mut Vec<String>,
+        done: &mut HashSet<String>,
+        cycles: &mut BTreeSet<Vec<String>>,
+    ) {
+        if let Some(idx) = path.iter().position(|n| n == name) {
+            let mut cycle = path[idx..].to_vec();
+            let start = (0..cycle.len()).min_by_key(|i| &cycle[*i]).unwrap();
+            cycle.rotate_left(start);
+            cycle.push(cycle[0].clone());
+            cycles.insert(cycle);
+            return;
+        }
+        if done.contains(name) {
+            return;
+        }
+        if let Some(env) = config.environments.get(name) {
+            path.push(name.to_string());
+            for previous in env.propagated_from().iter().filter(|p| *p != name) {
+                visit(config, previous, path, done, cycles);
+            }
+            path.pop();
+        }
+        done.insert(name.to_string());
+    }
+
+    let mut names: Vec<_> = config.environments.keys().collect();
+    names.sort();
+    let mut done = HashSet::new();
+    let mut cycles = BTreeSet::new();
+    for name in names {
+        visit(config, name, &mut Vec::new(), &mut done, &mut cycles);
+    }
+    cycles.into_iter().collect()
+}
+
+/// Locates values in the raw yaml so diagnostics can point at a line.
+struct Source<'a> {
+    lines: Vec<&'a str>,
+}
+
+impl<'a> Source<'a> {
+    fn new(content: &'a str) -> Self {
+        Self {
+            lines: content.lines().collect(),
+        }
+    }
+
+    /// 1-based line of the list item `needle` below `key` in the block of `env`.
+    /// Falls back to the line of `key` and then to the line of `env`.
+    fn line_of(&self, env: &str, key: &str, needle: Option<&str>) -> Option<usize> {
+        let (start, end) = self.env_block(env)?;
+        let key_idx = (start..end).find(|idx| {
+            let line = self.lines[*idx].trim_start();
+            line.starts_with(&format!("{}:", key))
+        });
+        let found = match (key_idx, needle) {
+            (Some(key_idx), Some(needle)) => {
+                // List items may sit at the indentation of their key.
+                let indent = indentation(self.lines[key_idx]);
+                let key_end = (key_idx + 1..end)
+                    .find(|idx| {
+                        let line = self.lines[*idx];
+                        let trimmed = line.trim_start();
+                        !trimmed.is_empty()
+                            && !trimmed.starts_with('#')
+                            && !trimmed.starts_with('-')
+                            && indentation(line) <= indent
+                    })
+                    .unwrap_or(end);
+                (key_idx + 1..key_end)
+                    .find(|idx| is_list_item(self.lines[*idx], needle))
+                    .or(Some(key_idx))
+            }
+            (key_idx, _) => key_idx,
+        };
+        Some(found.unwrap_or(start) + 1)
+    }
+
+    fn env_block(&self, env: &str) -> Option<(usize, usize)> {
+        let start = self.lines.iter().position(|line| {
+            let trimmed = line.trim_start();
+            line.len() != trimmed.len()
+                && [
+                    format!("{}:", env),
+                    format!("\"{}\":", env),
+                    format!("'{}':", env),
+                ]
+                .iter()
+                .any(|key| trimmed.starts_with(key.as_str()))
+        })?;
+        let indent = indentation(self.lines[start]);
+        let end = (start + 1..self.lines.len())
+            .find(|idx| {
+                let line = self.lines[*idx];
+                !line.trim().is_empty()
+                    && !line.trim_start().starts_with('#')
+                    && indentation(line) <= indent
+            })
+            .unwrap_or(self.lines.len());
+        Some((start, end))
+    }
+}
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// Whether `line` is the yaml list item `- <value>`, quoted or not.
+fn is_list_item(line: &str, value: &str) -> bool {
+    let item = match line.trim().strip_prefix('-') {
+        Some(item) => item.trim(),
+        None => return false,
+    };
+    ['"', '\'']
+        .iter()
+        .find_map(|quote| item.strip_prefix(*quote)?.strip_suffix(*quote))
+        .unwrap_or(item)
+        == value
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lint_str(conf: &str, files: &[&str]) -> Vec<String> {
+        let config = Config::from_reader_unchecked(conf.as_bytes()).unwrap();
+        let files: Vec<_> = files.iter().map(PathBuf::from).collect();
+        lint("cepler.yml", conf, &config, &files)
+            .into_iter()
+            .map(|d| d.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn valid_config() {
+        let conf = r#"environments:
+  testflight:
+    latest:
+    - file.yml
+  staging:
+    passed: testflight
+    propagated:
+    - file.yml"#;
+
+        assert!(lint_str(conf, &["file.yml"]).is_empty());
+    }
+
+    #[test]
+    fn reports_problems_by_line() {
+        let conf = r#"environments:
+  testflight:
+    passed: staging
+    latest:
+    - "[file.yml"
+    - missing.yml
+  staging:
+    passed: testflight
+    propagated:
+    - file.yml
+    latest:
+    - "*.yml""#;
+
+        let diagnostics = lint_str(conf, &["file.yml"]);
+        assert_eq!(diagnostics.len(), 4);
+        assert!(diagnostics[0].starts_with("cepler.yml:5: Environment 'testflight' has malformed"));
+        assert!(diagnostics[1].starts_with("cepler.yml:6: Environment 'testflight' pattern"));
+        assert!(
+            diagnostics[2]
+                == "cepler.yml:8: Cycle in 'passed' chain: staging -> testflight -> staging"
+        );
+        assert!(diagnostics[3].starts_with("cepler.yml:12: Environment 'staging' latest pattern"));
+    }
+
+    #[test]
+    fn points_at_the_exact_list_item() {
+        let conf = r#"environments:
+  testflight:
+    latest:
+    - other/file.yml
+    - 'file.yml'
+  staging:
+    passed: testflight
+    latest: [missing.yml]
+    propagated:
+    - other/file.yml
+    - missing.yml"#;
+
+        let diagnostics = lint_str(conf, &["other/file.yml"]);
+        assert_eq!(diagnostics.len(), 3);
+        assert!(
+            diagnostics[0].starts_with("cepler.yml:5: Environment 'testflight' pattern 'file.yml'")
+        );
+        assert!(
+            diagnostics[1].starts_with("cepler.yml:8: Environment 'staging' pattern 'missing.yml'")
+        );
+        assert!(diagnostics[2]
+            .starts_with("cepler.yml:11: Environment 'staging' pattern 'missing.yml'"));
+    }
+}
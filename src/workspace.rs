@@ -1,47 +1,543 @@
-use super::{config::*, database::*, repo::*};
-use anyhow::*;
-use std::collections::HashMap;
-use std::path::Path;
+use super::{
+    cache::Cache,
+    config::*,
+    database::*,
+    error::{self, CeplerError},
+    repo::*,
+    state_store::{state_store, RecordedState},
+};
+use anyhow::{Context, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 
+/// Entry point for embedding cepler. Bundles the repository, the config and the recorded
+/// state and exposes the operations of the cli as methods returning typed results.
+///
+/// ```no_run
+/// let ws = cepler::Workspace::builder()
+///     .repo_path("/path/to/repo")
+///     .config_path("cepler.yml")
+///     .build()?;
+/// if let Some(check) = ws.check("staging")? {
+///     println!("Deploy trigger {}", check.state_id.head_commit);
+/// }
+/// # Ok::<(), cepler::CeplerError>(())
+/// ```
 pub struct Workspace {
+    repo_path: PathBuf,
     path_to_config: String,
-    scope: String,
+    config: Config,
+    gates: Option<GatesConfig>,
     ignore_queue: bool,
     db: Database,
+    cache: Cache,
+    warnings: RefCell<Vec<String>>,
 }
 
+#[derive(Debug, Clone, Serialize)]
 pub struct StateId {
     pub head_commit: String,
     pub version: u32,
 }
 
-impl Workspace {
-    pub fn new(scope: &str, path_to_config: String, ignore_queue: bool) -> Result<Self> {
-        Ok(Self {
-            db: Database::open(scope, &path_to_config, ignore_queue)?,
-            scope: scope.to_string(),
+/// Returned by `Workspace::check` when the environment needs deploying.
+#[derive(Debug, Serialize)]
+pub struct CheckResult {
+    pub state_id: StateId,
+    pub diffs: Vec<FileDiff>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PrepareResult {
+    pub head_commit: String,
+    #[serde(serialize_with = "serialize_idents")]
+    pub files: Vec<FileIdent>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReproduceResult {
+    pub state_id: StateId,
+    #[serde(serialize_with = "serialize_idents")]
+    pub files: Vec<FileIdent>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecordResult {
+    pub state_id: StateId,
+    pub diffs: Vec<FileDiff>,
+    pub state_file: String,
+    pub committed: bool,
+    pub pushed: bool,
+    /// Set when a pending `rollback` was recorded.
+    pub rollback_of: Option<u32>,
+}
+
+/// Overview of one environment as returned by `Workspace::status`.
+#[derive(Debug, Serialize)]
+pub struct EnvironmentStatus {
+    pub name: String,
+    /// `None` if the environment was never recorded.
+    pub version: Option<u32>,
+    pub head_commit: Option<String>,
+    /// The head commit of each upstream environment the recorded state was propagated from.
+    pub propagated_heads: BTreeMap<String, String>,
+    pub any_dirty: bool,
+    pub queue_length: usize,
+    pub pending: bool,
+    /// Upstream environment that has to be recorded before `check` can run.
+    pub blocked_by: Option<String>,
+}
+
+/// What `record` would persist for an environment as returned by `Workspace::preview`.
+#[derive(Debug, Serialize)]
+pub struct Preview {
+    pub environment: String,
+    /// The state `check` would report, `None` if there is nothing new to deploy.
+    pub state_id: Option<StateId>,
+    /// The recorded state selected from each upstream environment.
+    pub upstreams: Vec<PropagationTarget>,
+    pub diffs: Vec<FileDiff>,
+}
+
+/// Where a file in the state of an environment comes from as returned by `Workspace::explain`.
+#[derive(Debug, Serialize)]
+pub struct Explanation {
+    pub environment: String,
+    pub file: String,
+    /// The `latest` globs matching the file.
+    pub latest: Vec<String>,
+    pub upstreams: Vec<UpstreamFile>,
+    /// The ignore list entry matching the file.
+    pub ignored_by: Option<String>,
+    /// `latest` or the upstream environment the file is taken from, `None` if the file
+    /// isn't part of the state.
+    pub source: Option<String>,
+    pub from_commit: Option<String>,
+    pub message: Option<String>,
+    /// Why `from_commit` was picked, only set for `latest` files.
+    pub change: Option<FileChange>,
+}
+
+/// The file in the state an upstream environment propagates.
+#[derive(Debug, Serialize)]
+pub struct UpstreamFile {
+    pub upstream: String,
+    /// The `propagated` globs matching the file.
+    pub patterns: Vec<String>,
+    /// The selected state of the upstream environment, `None` if it wasn't recorded yet.
+    pub state: Option<PropagationTarget>,
+    /// `None` if the file isn't part of the selected state.
+    pub from_commit: Option<String>,
+    /// `latest` or the upstream environment that takes precedence for the file.
+    pub shadowed_by: Option<String>,
+}
+
+/// The environments a range of commits triggers as returned by `Workspace::impact`.
+#[derive(Debug, Serialize)]
+pub struct Impact {
+    /// `None` if the range starts at the first commit.
+    pub from: Option<String>,
+    pub to: String,
+    /// All paths changed in the range.
+    pub files: Vec<String>,
+    /// Every environment, upstream environments first.
+    pub environments: Vec<EnvironmentImpact>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EnvironmentImpact {
+    pub name: String,
+    /// Changed files matching `latest` globs that are deployed as soon as the range is merged.
+    pub immediate: Vec<String>,
+    /// Changed files that reach the environment once the upstream environment they are
+    /// propagated from was recorded.
+    pub propagated: BTreeMap<String, Vec<String>>,
+}
+
+impl EnvironmentImpact {
+    pub fn affected(&self) -> bool {
+        !self.immediate.is_empty() || !self.propagated.is_empty()
+    }
+}
+
+/// The environments and their `passed` relationships as returned by `Workspace::graph`.
+#[derive(Debug, Serialize)]
+pub struct Graph {
+    /// Upstream environments first.
+    pub nodes: Vec<EnvironmentStatus>,
+    pub edges: Vec<GraphEdge>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GraphEdge {
+    pub from: String,
+    pub to: String,
+    /// The `propagated` globs of `to` that apply to `from`.
+    pub files: Vec<String>,
+}
+
+/// The content change of one file as returned by `Workspace::diff`.
+#[derive(Debug, Serialize)]
+pub struct ContentDiff {
+    pub file: String,
+    /// `latest` or the upstream environment the file is propagated from.
+    pub source: String,
+    /// The content in the recorded state, `None` if the file was added.
+    pub old_hash: Option<FileHash>,
+    /// The content `prepare` would produce, `None` if the file was removed.
+    pub new_hash: Option<FileHash>,
+    pub insertions: usize,
+    pub deletions: usize,
+    /// Unified diff of the content.
+    pub patch: String,
+}
+
+/// The files that differ between the recorded states of two environments
+/// as returned by `Workspace::compare`.
+#[derive(Debug, Serialize)]
+pub struct Comparison {
+    pub environments: (String, String),
+    pub files: Vec<FileComparison>,
+    /// Number of files with the same content in both environments.
+    pub identical: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileComparison {
+    pub file: String,
+    /// `None` if the file is missing in the first environment.
+    pub first: Option<ComparedFile>,
+    /// `None` if the file is missing in the second environment.
+    pub second: Option<ComparedFile>,
+    /// Unified diff from the first to the second environment, if requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub patch: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ComparedFile {
+    /// `latest` or the upstream environment the file was propagated from.
+    pub source: String,
+    pub file_hash: Option<FileHash>,
+    pub from_commit: String,
+}
+
+/// One recorded version of an environment as returned by `Workspace::history`.
+#[derive(Debug, Serialize)]
+pub struct HistoryEntry {
+    pub state_id: StateId,
+    /// Commit time of the commit that recorded the state.
+    pub recorded_at: DateTime<Utc>,
+    /// The commit that changed the state file.
+    pub state_commit: String,
+    pub rollback_of: Option<u32>,
+    /// Changes compared to the previous recorded version.
+    pub diffs: Vec<FileDiff>,
+}
+
+#[derive(Debug, Default)]
+pub struct HistoryOptions {
+    /// Only return versions recorded at or after this time.
+    pub since: Option<DateTime<Utc>>,
+    /// Return at most this many versions.
+    pub limit: Option<usize>,
+}
+
+pub const DEFAULT_PUSH_RETRIES: u32 = 3;
+
+pub struct RecordOptions {
+    /// Commit the state file after recording.
+    pub commit: bool,
+    /// Checkout all files to HEAD after recording.
+    pub reset_head: bool,
+    /// Rebase onto and push to the remote after recording.
+    pub push: Option<GitConfig>,
+    /// How often to fetch, rebase and push again when the remote moved on while pushing.
+    pub push_retries: u32,
+}
+
+impl Default for RecordOptions {
+    fn default() -> Self {
+        Self {
+            commit: true,
+            reset_head: false,
+            push: None,
+            push_retries: DEFAULT_PUSH_RETRIES,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct WorkspaceBuilder {
+    repo_path: Option<PathBuf>,
+    config_path: Option<String>,
+    config: Option<Config>,
+    gates: Option<GatesConfig>,
+    ignore_queue: bool,
+    state_store: Option<StateStoreKind>,
+    state_ref: Option<String>,
+    no_cache: bool,
+}
+
+impl WorkspaceBuilder {
+    /// Path to the repository. Discovered from the current directory when not set.
+    pub fn repo_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.repo_path = Some(path.into());
+        self
+    }
+
+    /// Path to the config file relative to the root of the repository. Defaults to `cepler.yml`.
+    pub fn config_path(mut self, path: impl Into<String>) -> Self {
+        self.config_path = Some(path.into());
+        self
+    }
+
+    /// Use an already loaded config instead of reading it from `config_path`.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    pub fn gates(mut self, gates: GatesConfig) -> Self {
+        self.gates = Some(gates);
+        self
+    }
+
+    pub fn ignore_queue(mut self, ignore_queue: bool) -> Self {
+        self.ignore_queue = ignore_queue;
+        self
+    }
+
+    /// Overrides `state_store` of the config.
+    pub fn state_store(mut self, kind: StateStoreKind) -> Self {
+        self.state_store = Some(kind);
+        self
+    }
+
+    /// Overrides `state_ref` of the config.
+    pub fn state_ref(mut self, state_ref: impl Into<String>) -> Self {
+        self.state_ref = Some(state_ref.into());
+        self
+    }
+
+    /// Don't read or write `.git/cepler-cache`. Results are identical, only slower.
+    pub fn no_cache(mut self, no_cache: bool) -> Self {
+        self.no_cache = no_cache;
+        self
+    }
+
+    /// Removes everything cached in `.git/cepler-cache` of the repository at `repo_path`.
+    /// Returns `false` if there was nothing to remove.
+    pub fn clear_cache(self) -> error::Result<bool> {
+        let repo = Repo::open(self.repo_path.as_deref(), None)?;
+        Ok(Cache::clear(&repo)?)
+    }
+
+    pub fn build(self) -> error::Result<Workspace> {
+        let repo = Repo::open(self.repo_path.as_deref(), None)?;
+        let repo_path = repo.workdir().to_path_buf();
+        let path_to_config = self.config_path.unwrap_or_else(|| "cepler.yml".to_string());
+        let config = match self.config {
+            Some(config) => config,
+            None => Config::from_file(repo_path.join(&path_to_config))?,
+        };
+        let store = state_store(
+            self.state_store.unwrap_or(config.state_store),
+            self.state_ref.as_deref().or(config.state_ref.as_deref()),
+        );
+        Ok(Workspace {
+            db: Database::open(
+                &repo,
+                store,
+                &config.scope,
+                &path_to_config,
+                self.ignore_queue,
+            )?,
+            repo_path,
             path_to_config,
-            ignore_queue,
+            config,
+            gates: self.gates,
+            ignore_queue: self.ignore_queue,
+            // Results computed up to a shallow boundary change once the clone is deepened.
+            cache: if self.no_cache || repo.is_shallow() {
+                Cache::disabled()
+            } else {
+                Cache::open(&repo)
+            },
+            warnings: RefCell::new(Vec::new()),
         })
     }
+}
+
+impl Workspace {
+    pub fn builder() -> WorkspaceBuilder {
+        WorkspaceBuilder::default()
+    }
 
-    pub fn ls(&self, env: &EnvironmentConfig, gate: Option<String>) -> Result<Vec<String>> {
-        let repo = Repo::open(gate)?;
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Updates the recorded state from the remote. Only needed for the `branch` and
+    /// `notes` state stores as the `tree` store is updated together with the repository.
+    pub fn fetch_state(&mut self, config: &GitConfig) -> error::Result<()> {
+        let repo = self.open_repo(None)?;
+        Ok(self.db.fetch(&repo, config)?)
+    }
+
+    /// Warnings collected by the operations since the last call.
+    pub fn take_warnings(&self) -> Vec<String> {
+        self.warnings.take()
+    }
+
+    pub fn state_store(&self) -> StateStoreKind {
+        self.db.state_store()
+    }
+
+    /// The root of the working tree all operations are resolved against.
+    pub fn repo_path(&self) -> &Path {
+        &self.repo_path
+    }
+
+    /// The last recorded state of `env`, if any.
+    pub fn latest(&self, env: &str) -> error::Result<Option<StateId>> {
+        let env = self.environment(env)?;
+        Ok(self
+            .db
+            .get_current_state(&env.name)
+            .map(|(version, state)| StateId {
+                version,
+                head_commit: state.head_commit.clone().inner(),
+            }))
+    }
+
+    /// Commits referenced by the recorded states that no longer exist in the repository.
+    /// With `fix` they are re-anchored to existing commits with identical content
+    /// and the states are persisted.
+    pub fn doctor(&mut self, fix: bool) -> error::Result<Vec<DanglingCommit>> {
+        let repo = self.open_repo(None)?;
+        Ok(self.db.repair(&repo, &self.config.scope, fix)?)
+    }
+
+    /// The status of every environment, upstream environments first.
+    pub fn status(&self) -> error::Result<Vec<EnvironmentStatus>> {
+        let mut ret = Vec::new();
+        for env in self.environments_in_order() {
+            let (pending, blocked_by) = match self.check(&env.name) {
+                Ok(check) => (check.is_some(), None),
+                Err(CeplerError::PreviousEnvironmentNotDeployed(previous)) => {
+                    (false, Some(previous))
+                }
+                Err(e @ CeplerError::UpstreamsDisagree(..)) => {
+                    self.warn(e.to_string());
+                    (false, None)
+                }
+                Err(e) => return Err(e),
+            };
+            let current = self.db.get_current_state(&env.name);
+            ret.push(EnvironmentStatus {
+                name: env.name.clone(),
+                version: current.map(|(version, _)| version),
+                head_commit: current.map(|(_, state)| state.head_commit.clone().inner()),
+                propagated_heads: current
+                    .map(|(_, state)| {
+                        env.propagated_from()
+                            .iter()
+                            .filter_map(|upstream| {
+                                state
+                                    .propagated_head_from(upstream)
+                                    .map(|head| (upstream.clone(), head.clone().inner()))
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                any_dirty: current.map(|(_, state)| state.any_dirty()).unwrap_or(false),
+                queue_length: self.db.propagation_queue_len(&env.name),
+                pending,
+                blocked_by,
+            });
+        }
+        Ok(ret)
+    }
+
+    /// The status of every environment together with the `passed` relationships between them.
+    pub fn graph(&self) -> error::Result<Graph> {
+        let edges = self
+            .environments_in_order()
+            .into_iter()
+            .flat_map(|env| {
+                env.propagated_from().iter().map(move |upstream| GraphEdge {
+                    from: upstream.clone(),
+                    to: env.name.clone(),
+                    files: env.propagated_files_from(upstream).to_vec(),
+                })
+            })
+            .collect();
+        Ok(Graph {
+            nodes: self.status()?,
+            edges,
+        })
+    }
+
+    /// The versions of `env` recorded in the git history of its state file, newest first.
+    pub fn history(&self, env: &str, options: HistoryOptions) -> error::Result<Vec<HistoryEntry>> {
+        let env = self.environment(env)?;
+        let states = self.recorded_states(&self.open_repo(None)?, env)?;
+        let mut ret = Vec::new();
+        for (idx, (commit, time, state)) in states.iter().enumerate() {
+            if options
+                .limit
+                .map(|limit| ret.len() >= limit)
+                .unwrap_or(false)
+            {
+                break;
+            }
+            // Versions aren't ordered by time after rollbacks or with clock skew.
+            let recorded_at = Utc.timestamp_opt(*time, 0).single().unwrap_or_default();
+            if options
+                .since
+                .map(|since| recorded_at < since)
+                .unwrap_or(false)
+            {
+                continue;
+            }
+            let diffs = match states.get(idx + 1) {
+                Some((_, _, previous)) => state.current().diff(previous.current()),
+                None => added_files(state.current()),
+            };
+            ret.push(HistoryEntry {
+                state_id: StateId {
+                    version: state.version(),
+                    head_commit: state.current().head_commit.clone().inner(),
+                },
+                recorded_at,
+                state_commit: commit.clone().inner(),
+                rollback_of: state.current().rollback_of,
+                diffs,
+            });
+        }
+        Ok(ret)
+    }
+
+    /// The files relevant to `env` at the gate, each with the environment it is taken from.
+    pub fn ls(&self, env: &str) -> error::Result<Vec<FileIdent>> {
+        let (env, repo) = self.env_and_repo(env)?;
         let new_env_state = self.construct_env_state(&repo, env, false)?;
-        Ok(new_env_state.files.into_keys().map(|k| k.name()).collect())
+        Ok(new_env_state.files.into_keys().collect())
     }
 
-    pub fn check(
-        &self,
-        env: &EnvironmentConfig,
-        gate: Option<String>,
-    ) -> Result<Option<(StateId, Vec<FileDiff>)>> {
-        let repo = Repo::open(gate)?;
-        if let Some(previous_env) = env.propagated_from() {
-            self.db.get_current_state(previous_env).context(format!(
-                "Previous environment '{}' not deployed yet",
-                previous_env
-            ))?;
+    /// Returns `None` if there is nothing new to deploy.
+    pub fn check(&self, env: &str) -> error::Result<Option<CheckResult>> {
+        let (env, repo) = self.env_and_repo(env)?;
+        for previous_env in env.propagated_from() {
+            if self.db.get_current_state(previous_env).is_none() {
+                return Err(CeplerError::PreviousEnvironmentNotDeployed(
+                    previous_env.clone(),
+                ));
+            }
         }
         let new_env_state = self.construct_env_state(&repo, env, false)?;
         let (version, diffs) = if let Some((version, last)) = self.db.get_current_state(&env.name) {
@@ -51,125 +547,553 @@ impl Workspace {
             }
             (version + 1, diffs)
         } else {
-            (
-                1,
-                new_env_state
-                    .files
-                    .iter()
-                    .map(|(ident, state)| FileDiff {
-                        ident: ident.clone(),
-                        current_state: Some(state.clone()),
-                        added: true,
-                    })
-                    .collect(),
-            )
+            (1, added_files(&new_env_state))
         };
-        for diff in diffs.iter() {
-            let name = diff.ident.name();
-            if diff.added {
-                eprintln!("File {} was added", name)
-            } else if diff.current_state.is_some() {
-                eprintln!("File {} changed", name)
-            } else {
-                eprintln!("File {} was removed", name)
-            }
-        }
-        Ok(Some((
-            StateId {
+        Ok(Some(CheckResult {
+            state_id: StateId {
                 version,
                 head_commit: new_env_state.head_commit.inner(),
             },
             diffs,
-        )))
+        }))
     }
 
-    pub fn reproduce(&self, env: &EnvironmentConfig, force_clean: bool) -> Result<StateId> {
-        let repo = Repo::open(None)?;
-        if let Some((version, last_state)) = self.db.get_current_state(&env.name) {
-            if force_clean {
-                repo.checkout_gate(&[], &self.ignore_list(), true)?;
-            }
-            for (ident, state) in last_state.files.iter() {
-                repo.checkout_file_from(&ident.name(), &state.from_commit)?;
+    /// Like `check` but also explains which recorded state of each upstream
+    /// environment would be propagated. Doesn't touch the working directory.
+    pub fn preview(&self, env: &str) -> error::Result<Preview> {
+        let check = self.check(env)?;
+        let (env, repo) = self.env_and_repo(env)?;
+        let database = self.db.open_env_from_commit(
+            &self.path_to_config,
+            self.ignore_queue,
+            &self.config.scope,
+            env,
+            repo.gate_commit_hash(),
+            &repo,
+        )?;
+        let upstreams = database.explain_target_propagated_state(
+            &env.name,
+            env.ignore_queue,
+            &upstream_patterns(env),
+        );
+        let (state_id, diffs) = match check {
+            Some(CheckResult { state_id, diffs }) => (Some(state_id), diffs),
+            None => (None, Vec::new()),
+        };
+        Ok(Preview {
+            environment: env.name.clone(),
+            state_id,
+            upstreams,
+            diffs,
+        })
+    }
+
+    /// Explains why `file` is (or isn't) part of the state of `env` and where its content
+    /// comes from.
+    pub fn explain(&self, env: &str, file: &str) -> error::Result<Explanation> {
+        let (env, repo) = self.env_and_repo(env)?;
+        let file = file.trim_start_matches("./").to_string();
+        let path = Path::new(&file);
+        let latest = env
+            .head_files()
+            .iter()
+            .zip(env.head_file_patterns())
+            .filter(|(_, p)| p.matches_path_with(path, MATCH_OPTIONS))
+            .map(|(glob, _)| glob.clone())
+            .collect();
+        let ignored_by = self
+            .state_ignore_list(&self.db.state_dir)
+            .into_iter()
+            .find(|p| p.matches_path_with(path, MATCH_OPTIONS))
+            .map(|p| p.as_str().to_string());
+
+        let state = self.construct_env_state(&repo, env, false)?;
+        let resolved = state.files.iter().find(|(ident, _)| ident.name() == file);
+        let source = resolved.map(|(ident, _)| ident.source().to_string());
+        let change = match resolved {
+            Some((ident, file_state)) if !ident.propagated() => {
+                Some(repo.file_change(path, &file_state.from_commit, self.config.history)?)
             }
-            Ok(StateId {
-                version,
-                head_commit: last_state.head_commit.clone().inner(),
+            _ => None,
+        };
+
+        let database = self.db.open_env_from_commit(
+            &self.path_to_config,
+            self.ignore_queue,
+            &self.config.scope,
+            env,
+            repo.gate_commit_hash(),
+            &repo,
+        )?;
+        let upstream_patterns = upstream_patterns(env);
+        let mut targets = database
+            .explain_target_propagated_state(&env.name, env.ignore_queue, &upstream_patterns)
+            .into_iter();
+        let passed_states =
+            database.get_target_propagated_state(&env.name, env.ignore_queue, &upstream_patterns);
+        let mut upstreams = Vec::new();
+        for (upstream, _) in upstream_patterns.iter() {
+            let patterns: Vec<_> = env
+                .propagated_files_from(upstream)
+                .iter()
+                .zip(env.propagated_file_patterns(upstream))
+                .filter(|(_, p)| p.matches_with(&file, MATCH_OPTIONS))
+                .map(|(glob, _)| glob.clone())
+                .collect();
+            let state = match targets.as_slice().first() {
+                Some(target) if &target.upstream == upstream => targets.next(),
+                _ => None,
+            };
+            let from_commit = passed_states
+                .iter()
+                .find(|(name, _)| name == upstream)
+                .and_then(|(_, state)| {
+                    state
+                        .files
+                        .iter()
+                        .find(|(ident, f)| ident.name() == file && f.file_hash.is_some())
+                })
+                .map(|(_, f)| f.from_commit.clone().inner());
+            let shadowed_by = match source.as_ref() {
+                Some(source)
+                    if source != upstream && from_commit.is_some() && !patterns.is_empty() =>
+                {
+                    Some(source.clone())
+                }
+                _ => None,
+            };
+            upstreams.push(UpstreamFile {
+                upstream: upstream.clone(),
+                patterns,
+                state,
+                from_commit,
+                shadowed_by,
+            });
+        }
+
+        Ok(Explanation {
+            environment: env.name.clone(),
+            file,
+            latest,
+            upstreams,
+            ignored_by,
+            source,
+            from_commit: resolved.map(|(_, f)| f.from_commit.clone().inner()),
+            message: resolved.map(|(_, f)| f.message.clone()),
+            change,
+        })
+    }
+
+    /// Matches the paths changed in `range` (see `git help revisions`) against the globs of
+    /// every environment. Files reach downstream environments via their `propagated` globs
+    /// unless a `latest` glob of the downstream environment matches them.
+    pub fn impact(&self, range: &str) -> error::Result<Impact> {
+        let repo = self.open_repo(None)?;
+        let (from, to) = repo.resolve_range(range)?;
+        let ignore_list = self.state_ignore_list(&self.db.state_dir);
+        let files: Vec<_> = repo
+            .changed_paths(from.as_ref(), &to)?
+            .into_iter()
+            .filter(|file| {
+                !ignore_list
+                    .iter()
+                    .any(|p| p.matches_with(file, MATCH_OPTIONS))
             })
+            .collect();
+        let mut reached: HashMap<&str, Vec<&String>> = HashMap::new();
+        let mut environments = Vec::new();
+        for env in self.environments_in_order() {
+            let head_patterns: Vec<_> = env.head_file_patterns().collect();
+            let (immediate, rest): (Vec<_>, Vec<_>) = files.iter().partition(|file| {
+                head_patterns
+                    .iter()
+                    .any(|p| p.matches_with(file, MATCH_OPTIONS))
+            });
+            let mut propagated = BTreeMap::new();
+            let mut env_reached = immediate.clone();
+            for upstream in env.propagated_from() {
+                let patterns: Vec<_> = env.propagated_file_patterns(upstream).collect();
+                let from_upstream: Vec<_> = reached
+                    .get(upstream.as_str())
+                    .into_iter()
+                    .flatten()
+                    .filter(|file| {
+                        rest.contains(file)
+                            && !env_reached.contains(file)
+                            && patterns.iter().any(|p| p.matches_with(file, MATCH_OPTIONS))
+                    })
+                    .copied()
+                    .collect();
+                if !from_upstream.is_empty() {
+                    env_reached.extend(from_upstream.iter().copied());
+                    propagated.insert(
+                        upstream.clone(),
+                        from_upstream.into_iter().cloned().collect(),
+                    );
+                }
+            }
+            reached.insert(&env.name, env_reached);
+            environments.push(EnvironmentImpact {
+                name: env.name.clone(),
+                immediate: immediate.into_iter().cloned().collect(),
+                propagated,
+            });
+        }
+        Ok(Impact {
+            from: from.map(CommitHash::inner),
+            to: to.inner(),
+            files,
+            environments,
+        })
+    }
+
+    /// The content changes between the recorded state of `env` and the state `prepare`
+    /// would produce.
+    pub fn diff(&self, env: &str) -> error::Result<Vec<ContentDiff>> {
+        let (env, repo) = self.env_and_repo(env)?;
+        let new_env_state = self.construct_env_state(&repo, env, false)?;
+        let last = self.db.get_current_state(&env.name).map(|(_, state)| state);
+        let diffs = match last {
+            Some(last) => new_env_state.diff(last),
+            None => added_files(&new_env_state),
+        };
+        let mut ret = Vec::new();
+        for diff in diffs {
+            let name = diff.ident.name();
+            let old_hash = last
+                .and_then(|last| last.files.get(&diff.ident))
+                .and_then(|state| state.file_hash.clone());
+            let new_hash = diff.current_state.and_then(|state| state.file_hash);
+            let (patch, insertions, deletions) =
+                repo.diff_blobs(&name, old_hash.as_ref(), new_hash.as_ref())?;
+            ret.push(ContentDiff {
+                source: diff.ident.source().to_string(),
+                file: name,
+                old_hash,
+                new_hash,
+                insertions,
+                deletions,
+                patch,
+            });
+        }
+        Ok(ret)
+    }
+
+    /// Compares the recorded states of two environments. Files are matched by name
+    /// regardless of whether they are `latest` or propagated.
+    pub fn compare(&self, first: &str, second: &str, content: bool) -> error::Result<Comparison> {
+        let recorded = |env: &str| -> error::Result<BTreeMap<String, (&FileIdent, &FileState)>> {
+            let env = self.environment(env)?;
+            let (_, state) = self
+                .db
+                .get_current_state(&env.name)
+                .ok_or_else(|| CeplerError::NoStateRecorded(env.name.clone()))?;
+            Ok(state
+                .files
+                .iter()
+                .map(|(ident, file)| (ident.name(), (ident, file)))
+                .collect())
+        };
+        let mut first_files = recorded(first)?;
+        let second_files = recorded(second)?;
+        let repo = self.open_repo(None)?;
+        let compared = |(ident, file): (&FileIdent, &FileState)| ComparedFile {
+            source: ident.source().to_string(),
+            file_hash: file.file_hash.clone(),
+            from_commit: file.from_commit.clone().inner(),
+        };
+        let mut names: Vec<_> = first_files
+            .keys()
+            .chain(second_files.keys())
+            .cloned()
+            .collect();
+        names.sort();
+        names.dedup();
+        let mut files = Vec::new();
+        let mut identical = 0;
+        for name in names {
+            let a = first_files.remove(&name);
+            let b = second_files.get(&name).copied();
+            let hash =
+                |f: Option<(&FileIdent, &FileState)>| f.and_then(|(_, f)| f.file_hash.clone());
+            let (a_hash, b_hash) = (hash(a), hash(b));
+            if a.is_some() && b.is_some() && a_hash == b_hash {
+                identical += 1;
+                continue;
+            }
+            let patch = if content {
+                Some(repo.diff_blobs(&name, a_hash.as_ref(), b_hash.as_ref())?.0)
+            } else {
+                None
+            };
+            files.push(FileComparison {
+                file: name,
+                first: a.map(compared),
+                second: b.map(compared),
+                patch,
+            });
+        }
+        Ok(Comparison {
+            environments: (first.to_string(), second.to_string()),
+            files,
+            identical,
+        })
+    }
+
+    pub fn reproduce(&self, env: &str, force_clean: bool) -> error::Result<ReproduceResult> {
+        let env = self.environment(env)?;
+        let repo = self.open_repo(None)?;
+        if let Some((version, last_state)) = self.db.get_current_state(&env.name) {
+            Ok(self.checkout_state(&repo, version, last_state, force_clean)?)
         } else {
-            Err(anyhow!("No state recorded for {}", env.name))
+            Err(CeplerError::NoStateRecorded(env.name.clone()))
         }
     }
 
-    pub fn prepare(
+    /// Like `reproduce` but restores the files of an earlier recorded `version`.
+    pub fn reproduce_version(
         &self,
-        env: &EnvironmentConfig,
-        gate: Option<String>,
+        env: &str,
+        version: u32,
+        force_clean: bool,
+    ) -> error::Result<ReproduceResult> {
+        let env = self.environment(env)?;
+        let repo = self.open_repo(None)?;
+        let state = self.find_version(&repo, env, version)?;
+        Ok(self.checkout_state(&repo, version, &state, force_clean)?)
+    }
+
+    /// Restores the files of `version` and marks the environment as rolling back.
+    /// The next `record` of `env` persists that version as a new version instead of
+    /// the state at the gate.
+    pub fn rollback(
+        &self,
+        env: &str,
+        to_version: u32,
         force_clean: bool,
-    ) -> Result<()> {
-        let repo = Repo::open(gate)?;
+    ) -> error::Result<ReproduceResult> {
+        let result = self.reproduce_version(env, to_version, force_clean)?;
+        let repo = self.open_repo(None)?;
+        PendingRollback {
+            version: to_version,
+            recorded_version: self
+                .db
+                .get_current_state(env)
+                .map(|(version, _)| version)
+                .unwrap_or(0),
+        }
+        .write(&repo, env)?;
+        Ok(result)
+    }
+
+    pub fn prepare(&self, env: &str, force_clean: bool) -> error::Result<PrepareResult> {
+        let (env, repo) = self.env_and_repo(env)?;
         let head_patterns: Vec<_> = env.head_file_patterns().collect();
         repo.checkout_gate(&head_patterns, &self.ignore_list(), force_clean)?;
         let new_env_state = self.construct_env_state(&repo, env, false)?;
         for (ident, state) in new_env_state.files.iter() {
             if ident.propagated() {
-                repo.checkout_file_from(&ident.name(), &state.from_commit)?;
+                checkout_file(&repo, ident, state)?;
             }
         }
-        Ok(())
+        Ok(PrepareResult {
+            head_commit: new_env_state.head_commit.clone().inner(),
+            files: new_env_state.files.into_keys().collect(),
+        })
     }
 
-    pub fn record_env(
-        &mut self,
-        env: &EnvironmentConfig,
-        gate: Option<String>,
-        commit: bool,
-        reset: bool,
-        git_config: Option<GitConfig>,
-    ) -> Result<(StateId, Vec<FileDiff>)> {
-        eprintln!("Recording current state");
-        let repo = Repo::open(gate)?;
-        let new_env_state = self.construct_env_state(&repo, env, true)?;
+    pub fn record(&mut self, env: &str, options: RecordOptions) -> error::Result<RecordResult> {
+        let (env, repo) = self.env_and_repo(env)?;
+        let rollback = self.pending_rollback(&repo, &env.name)?;
+        let new_env_state = if let Some(rollback) = rollback.as_ref() {
+            let mut state = with_workdir_hashes(
+                &repo,
+                self.find_version(&repo, env, rollback.version)?,
+            );
+            state.rollback_of = Some(rollback.version);
+            state
+        } else {
+            self.construct_env_state(&repo, env, true)?
+        };
         let head_commit = new_env_state.head_commit.clone().inner();
         let diffs = if let Some((_, last_state)) = self.db.get_current_state(&env.name) {
             new_env_state.diff(last_state)
         } else {
-            new_env_state
-                .files
-                .iter()
-                .map(|(ident, state)| FileDiff {
-                    ident: ident.clone(),
-                    current_state: Some(state.clone()),
-                    added: true,
-                })
-                .collect()
+            added_files(&new_env_state)
         };
+        let name = env.name.clone();
+        let propagated_from = env.propagated_from().to_vec();
         let (version, state_file) = self.db.set_current_environment_state(
-            env.name.clone(),
-            env.propagated_from().cloned(),
+            &repo,
+            &self.config.scope,
+            name.clone(),
+            propagated_from,
             new_env_state,
+            options.commit,
         )?;
-        if commit {
-            eprintln!("Adding commit to repository to persist state");
-            repo.commit_state_file(&self.scope, state_file)?;
+        if rollback.is_some() {
+            PendingRollback::clear(&repo, &name)?;
         }
-        if reset {
-            eprintln!("Reseting head to have a clean workspace");
+        if options.reset_head {
             repo.checkout_head()?;
         }
-        if let Some(config) = git_config {
-            eprintln!("Pushing to remote");
-            if !repo.push(config)? {
-                eprintln!("... there was nothing new to push");
-            }
-        }
-        Ok((
-            StateId {
+        let pushed = if let Some(config) = options.push {
+            self.db.push(
+                &repo,
+                config,
+                &self.config.scope,
+                &name,
+                options.push_retries,
+            )?
+        } else {
+            false
+        };
+        Ok(RecordResult {
+            state_id: StateId {
                 head_commit,
                 version,
             },
             diffs,
-        ))
+            state_file,
+            committed: options.commit,
+            pushed,
+            rollback_of: rollback.map(|r| r.version),
+        })
+    }
+
+    /// The rollback of `env` left by `rollback`. A rollback is discarded if `env` was
+    /// recorded since, eg. from another clone.
+    fn pending_rollback(&self, repo: &Repo, env: &str) -> Result<Option<PendingRollback>> {
+        let rollback = match PendingRollback::read(repo, env)? {
+            Some(rollback) => rollback,
+            None => return Ok(None),
+        };
+        let current = self
+            .db
+            .get_current_state(env)
+            .map(|(version, _)| version)
+            .unwrap_or(0);
+        if current != rollback.recorded_version {
+            self.warn(format!(
+                "Discarding the rollback of '{}' to version {} as version {} was recorded since",
+                env, rollback.version, current
+            ));
+            PendingRollback::clear(repo, env)?;
+            return Ok(None);
+        }
+        Ok(Some(rollback))
+    }
+
+    /// Every version of `env` found in the history of the state store, newest first.
+    fn recorded_states(&self, repo: &Repo, env: &EnvironmentConfig) -> Result<Vec<RecordedState>> {
+        let mut states = self.db.history(repo, &env.name)?;
+        // Reverting a record brings back an older version, keep the latest commit per version.
+        let mut seen = std::collections::HashSet::new();
+        states.retain(|(_, _, state)| seen.insert(state.version()));
+        states.sort_by_key(|(_, _, state)| std::cmp::Reverse(state.version()));
+        Ok(states)
+    }
+
+    fn find_version(
+        &self,
+        repo: &Repo,
+        env: &EnvironmentConfig,
+        version: u32,
+    ) -> error::Result<DeployState> {
+        if let Some((current, state)) = self.db.get_current_state(&env.name) {
+            if current == version {
+                return Ok(state.clone());
+            }
+        }
+        self.recorded_states(repo, env)?
+            .into_iter()
+            .find(|(_, _, state)| state.version() == version)
+            .map(|(_, _, state)| state.current().clone())
+            .ok_or_else(|| CeplerError::VersionNotFound(env.name.clone(), version))
+    }
+
+    fn checkout_state(
+        &self,
+        repo: &Repo,
+        version: u32,
+        deploy_state: &DeployState,
+        force_clean: bool,
+    ) -> Result<ReproduceResult> {
+        if force_clean {
+            repo.checkout_gate(&[], &self.ignore_list(), true)?;
+        }
+        for (ident, state) in deploy_state.files.iter() {
+            checkout_file(repo, ident, state)?;
+        }
+        Ok(ReproduceResult {
+            state_id: StateId {
+                version,
+                head_commit: deploy_state.head_commit.clone().inner(),
+            },
+            files: deploy_state.files.keys().cloned().collect(),
+        })
+    }
+
+    /// Environments sorted by the length of their `passed` chain, then by name.
+    fn environments_in_order(&self) -> Vec<&EnvironmentConfig> {
+        let environments = &self.config.environments;
+        let mut depths: HashMap<&str, usize> = HashMap::new();
+        // Bounded by the number of environments so cycles can't loop forever.
+        for _ in 0..environments.len() {
+            for (name, env) in environments.iter() {
+                let depth = env
+                    .propagated_from()
+                    .iter()
+                    .map(|upstream| depths.get(upstream.as_str()).map(|d| d + 1).unwrap_or(1))
+                    .max()
+                    .unwrap_or(0)
+                    .min(environments.len());
+                depths.insert(name, depth);
+            }
+        }
+        let mut ret: Vec<_> = environments.values().collect();
+        ret.sort_by(|a, b| {
+            depths[a.name.as_str()]
+                .cmp(&depths[b.name.as_str()])
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        ret
+    }
+
+    fn environment(&self, env: &str) -> error::Result<&EnvironmentConfig> {
+        self.config
+            .environments
+            .get(env)
+            .ok_or_else(|| CeplerError::EnvironmentNotFound(env.to_string()))
+    }
+
+    /// Loads the config of `env` for resolving its state. Fan-in environments are
+    /// rejected while their upstreams disagree on the content of a shared file.
+    fn env_and_repo(&self, env: &str) -> error::Result<(&EnvironmentConfig, Repo)> {
+        let gate = if let Some(gates) = self.gates.as_ref() {
+            gates.get_gate(env)?
+        } else {
+            None
+        };
+        let env = self.environment(env)?;
+        let repo = self.open_repo(gate)?;
+        if env.propagated_from().len() > 1 {
+            self.db
+                .open_env_from_commit(
+                    &self.path_to_config,
+                    self.ignore_queue,
+                    &self.config.scope,
+                    env,
+                    repo.gate_commit_hash(),
+                    &repo,
+                )?
+                .ensure_upstreams_agree(&env.name, env.ignore_queue, &upstream_patterns(env))?;
+        }
+        Ok((env, repo))
+    }
+
+    fn open_repo(&self, gate: Option<String>) -> Result<Repo> {
+        Repo::open(Some(&self.repo_path), gate)
     }
 
     #[allow(clippy::redundant_closure)]
@@ -183,12 +1107,11 @@ impl Workspace {
         let database = self.db.open_env_from_commit(
             &self.path_to_config,
             self.ignore_queue,
-            &self.scope,
+            &self.config.scope,
             env,
             current_commit.clone(),
             repo,
         )?;
-
         let mut best_state = self.construct_state_for_commit(
             repo,
             current_commit.clone(),
@@ -196,19 +1119,97 @@ impl Workspace {
             &database,
             recording,
         )?;
-        repo.walk_commits_before(current_commit, |commit| {
-            if let Some(state) =
+        let current_state = best_state.clone();
+        let max_depth = env.max_history_depth.or(self.config.max_history_depth);
+        let oldest = match env
+            .max_history_age
+            .as_deref()
+            .or(self.config.max_history_age.as_deref())
+            .and_then(parse_age)
+        {
+            Some(age) => Some(repo.commit_time(&current_commit)? - age),
+            None => None,
+        };
+        let mut depth = 0;
+        let mut bound_hit = false;
+        // Commits in which everything the state is derived from has the same tree ids
+        // as the last equivalent commit are equivalent as well and can be skipped.
+        let mut best_tree_ids: Option<(Pathspec, Vec<Option<String>>)> = None;
+        let mut skipped = None;
+        let shallow_boundary =
+            repo.walk_commits_before(current_commit.clone(), self.config.history, |commit| {
+                depth += 1;
+                // The bound is only hit if the walk would continue past it.
+                let past_bound = max_depth.map(|max| depth > max).unwrap_or(false)
+                    || oldest.is_some() && Some(repo.commit_time(&commit)?) < oldest;
+                if let Some((pathspec, tree_ids)) = best_tree_ids.as_ref() {
+                    if !best_state.files.values().any(|f| f.dirty)
+                        && repo.tree_ids(&commit, pathspec)? == *tree_ids
+                    {
+                        if past_bound {
+                            bound_hit = true;
+                            return Ok(false);
+                        }
+                        skipped = Some(commit);
+                        return Ok(true);
+                    }
+                }
+                if let Some((state, pathspec)) =
+                    self.get_state_if_equivalent(&env.name, repo, &best_state, commit, recording)?
+                {
+                    if past_bound {
+                        bound_hit = true;
+                        return Ok(false);
+                    }
+                    best_tree_ids = match pathspec {
+                        Some(pathspec) => {
+                            let tree_ids = repo.tree_ids(&state.head_commit, &pathspec)?;
+                            Some((pathspec, tree_ids))
+                        }
+                        None => None,
+                    };
+                    best_state = state;
+                    skipped = None;
+                    Ok(true)
+                } else {
+                    Ok(false)
+                }
+            })?;
+        if bound_hit {
+            self.warn(format!(
+                "Reached the history bound of '{}' before finding the commit that triggered its state - using {} as the trigger",
+                env.name, current_commit
+            ));
+            return Ok(current_state);
+        }
+        if let Some(boundary) = shallow_boundary {
+            self.warn_shallow_boundary(&boundary);
+        }
+        if let Some(commit) = skipped {
+            if let Some((state, _)) =
                 self.get_state_if_equivalent(&env.name, repo, &best_state, commit, recording)?
             {
                 best_state = state;
-                Ok(true)
-            } else {
-                Ok(false)
             }
-        })?;
+        }
         Ok(best_state)
     }
 
+    /// Everything in the tree the state of `env` is derived from. `None` if the
+    /// state also depends on upstream states kept outside of the tree by a ref based store.
+    fn state_pathspec(&self, env: &EnvironmentConfig, state_dir: &str) -> Option<Pathspec> {
+        if self.db.state_store() != StateStoreKind::Tree && !env.propagated_from().is_empty() {
+            return None;
+        }
+        let head_patterns: Vec<_> = env.head_file_patterns().collect();
+        Some(Pathspec::from_patterns(
+            head_patterns
+                .iter()
+                .map(|p| p.as_str())
+                .chain([self.path_to_config.as_str(), state_dir]),
+        ))
+    }
+
     fn get_state_if_equivalent(
         &self,
         env_name: &str,
@@ -216,10 +1217,10 @@ impl Workspace {
         last_state: &DeployState,
         commit: CommitHash,
         recording: bool,
-    ) -> Result<Option<DeployState>> {
+    ) -> Result<Option<(DeployState, Option<Pathspec>)>> {
         let config = if let Some(config) =
             repo.get_file_content(commit.clone(), Path::new(&self.path_to_config), |bytes| {
-                Config::from_reader(bytes)
+                Ok(Config::from_reader(bytes)?)
             })? {
             config
         } else {
@@ -240,7 +1241,10 @@ impl Workspace {
         )?;
         let new_state = self.construct_state_for_commit(repo, commit, env, &database, recording)?;
         if last_state.diff(&new_state).is_empty() {
-            Ok(Some(new_state))
+            Ok(Some((
+                new_state,
+                self.state_pathspec(env, &database.state_dir),
+            )))
         } else {
             Ok(None)
         }
@@ -253,84 +1257,99 @@ impl Workspace {
         env: &EnvironmentConfig,
         database: &Database,
         recording: bool,
+    ) -> Result<DeployState> {
+        let upstreams = upstream_patterns(env);
+        let passed_states =
+            database.get_target_propagated_state(&env.name, env.ignore_queue, &upstreams);
+        let inputs = format!(
+            "{:?}|{}|{}|{}|{}",
+            env,
+            self.config.history,
+            self.path_to_config,
+            database.state_dir,
+            serde_json::to_string(&passed_states)?
+        );
+        let state = self.cache.state(&env.name, &commit, &inputs, || {
+            self.committed_state_for_commit(repo, commit.clone(), env, database, passed_states)
+        })?;
+        if recording {
+            Ok(with_workdir_hashes(repo, state))
+        } else {
+            Ok(state)
+        }
+    }
+
+    /// The state of `env` as committed at `commit`, ignoring the working directory.
+    fn committed_state_for_commit(
+        &self,
+        repo: &Repo,
+        commit: CommitHash,
+        env: &EnvironmentConfig,
+        database: &Database,
+        passed_states: Vec<(&str, &DeployState)>,
     ) -> Result<DeployState> {
         let mut new_env_state = DeployState::new(commit.clone());
         let mut inserted_files = HashMap::new();
-        if let Some(previous_env) = env.propagated_from() {
-            let patterns: Vec<_> = env.propagated_file_patterns().collect();
-            if let Some(passed_state) = database.get_target_propagated_state(
-                &env.name,
-                env.ignore_queue,
-                previous_env,
-                &patterns,
-            ) {
+        let upstreams = upstream_patterns(env);
+        let fan_in = passed_states.len() > 1;
+        for ((previous_env, passed_state), (_, patterns)) in
+            passed_states.into_iter().zip(upstreams.iter())
+        {
+            if fan_in {
+                new_env_state
+                    .propagated_heads
+                    .insert(previous_env.to_string(), passed_state.head_commit.clone());
+            } else {
                 new_env_state.propagated_head = Some(passed_state.head_commit.clone());
-                for (ident, prev_state) in passed_state.files.iter() {
-                    let name = ident.name();
-                    if let Some(last_hash) = prev_state.file_hash.as_ref() {
-                        if patterns
-                            .iter()
-                            .any(|p| p.matches_with(&name, MATCH_OPTIONS))
-                        {
-                            let (dirty, file_hash) = if recording {
-                                if let Some(file_hash) = hash_file(&name) {
-                                    (&file_hash != last_hash, Some(file_hash))
-                                } else {
-                                    (true, None)
-                                }
-                            } else {
-                                (false, Some(last_hash.clone()))
-                            };
-                            let file_state = FileState {
-                                dirty,
-                                file_hash,
-                                from_commit: prev_state.from_commit.clone(),
-                                message: prev_state.message.clone(),
-                            };
-                            let ident = FileIdent::new(name.clone(), Some(previous_env));
-                            inserted_files.insert(name.clone(), ident.clone());
-                            new_env_state.files.insert(ident, file_state);
-                        }
+            }
+            for (ident, prev_state) in passed_state.files.iter() {
+                let name = ident.name();
+                if inserted_files.contains_key(&name) {
+                    continue;
+                }
+                if let Some(last_hash) = prev_state.file_hash.as_ref() {
+                    if patterns
+                        .iter()
+                        .any(|p| p.matches_with(&name, MATCH_OPTIONS))
+                    {
+                        let file_state = FileState {
+                            dirty: false,
+                            file_hash: Some(last_hash.clone()),
+                            from_commit: prev_state.from_commit.clone(),
+                            message: prev_state.message.clone(),
+                        };
+                        let ident = FileIdent::new(name.clone(), Some(previous_env));
+                        inserted_files.insert(name.clone(), ident.clone());
+                        new_env_state.files.insert(ident, file_state);
                     }
                 }
             }
         }
-        let ignore_list = vec![
-            glob::Pattern::new(&self.path_to_config).unwrap(),
-            glob::Pattern::new(&format!("{}/*", database.state_dir)).unwrap(),
-        ];
-        repo.all_files(commit.clone(), |file_hash, path| {
-            if env
-                .head_file_patterns()
+        let ignore_list = self.state_ignore_list(&database.state_dir);
+        let head_patterns: Vec<_> = env.head_file_patterns().collect();
+        let pathspec = Pathspec::from_patterns(head_patterns.iter().map(|p| p.as_str()));
+        repo.all_files(commit.clone(), &pathspec, |file_hash, path| {
+            if head_patterns
+                .iter()
                 .any(|p| p.matches_path_with(path, MATCH_OPTIONS))
                 && !ignore_list
                     .iter()
                     .any(|p| p.matches_path_with(path, MATCH_OPTIONS))
             {
-                let (from_commit, message) = repo.find_last_changed_commit(path, commit.clone())?;
-                let state = if recording {
-                    if let Some(on_disk_hash) = hash_file(path) {
-                        FileState {
-                            dirty: file_hash != on_disk_hash,
-                            file_hash: Some(on_disk_hash),
-                            from_commit,
-                            message,
-                        }
-                    } else {
-                        FileState {
-                            dirty: true,
-                            file_hash: None,
-                            from_commit,
-                            message,
-                        }
-                    }
-                } else {
-                    FileState {
-                        dirty: false,
-                        file_hash: Some(file_hash),
-                        from_commit,
-                        message,
-                    }
+                let (from_commit, message) =
+                    self.cache
+                        .last_changed(self.config.history, &commit, path, || {
+                            repo.find_last_changed_commit(path, commit.clone(), self.config.history)
+                        })?;
+                if repo.is_shallow() && repo.is_shallow_boundary(&from_commit, self.config.history)
+                {
+                    self.warn_shallow_boundary(&from_commit);
+                }
+                let state = FileState {
+                    dirty: false,
+                    file_hash: Some(file_hash),
+                    from_commit,
+                    message,
                 };
                 let file_name = path.to_str().unwrap().to_string();
                 let ident = FileIdent::new(file_name, None);
@@ -344,12 +1363,119 @@ impl Workspace {
         Ok(new_env_state)
     }
 
+    fn warn(&self, warning: String) {
+        let mut warnings = self.warnings.borrow_mut();
+        if !warnings.contains(&warning) {
+            warnings.push(warning);
+        }
+    }
+
+    fn warn_shallow_boundary(&self, commit: &CommitHash) {
+        self.warn(format!(
+            "Reached the boundary of the shallow clone at {} - treating it as the first commit",
+            commit
+        ));
+    }
+
     fn ignore_list(&self) -> Vec<glob::Pattern> {
+        let mut ignore_list = self.state_ignore_list(&self.db.state_dir);
+        ignore_list.push(glob::Pattern::new(".git/*").unwrap());
+        ignore_list.push(glob::Pattern::new(".gitignore").unwrap());
+        ignore_list
+    }
+
+    /// Files that are never part of a state even if a `latest` glob matches them.
+    fn state_ignore_list(&self, state_dir: &str) -> Vec<glob::Pattern> {
         vec![
             glob::Pattern::new(&self.path_to_config).unwrap(),
-            glob::Pattern::new(&format!("{}/*", self.db.state_dir)).unwrap(),
-            glob::Pattern::new(".git/*").unwrap(),
-            glob::Pattern::new(".gitignore").unwrap(),
+            glob::Pattern::new(&format!("{}/*", state_dir)).unwrap(),
         ]
     }
 }
+
+/// Checks out the recorded content of a file. Falls back to the blob if the commit
+/// it was recorded from is gone, eg. because the history was rewritten.
+fn checkout_file(repo: &Repo, ident: &FileIdent, state: &FileState) -> Result<()> {
+    if repo.has_commit(&state.from_commit) {
+        return repo.checkout_file_from(&ident.name(), &state.from_commit);
+    }
+    match state.file_hash.as_ref() {
+        Some(hash) if repo.has_blob(hash) => repo.checkout_blob(&ident.name(), hash),
+        Some(_) => {
+            Err(CeplerError::DanglingCommit(ident.name(), state.from_commit.to_string()).into())
+        }
+        None => Ok(()),
+    }
+}
+
+/// Replaces the committed hash of every file with the one in the working directory
+/// and marks the files that differ as dirty.
+fn with_workdir_hashes(repo: &Repo, mut state: DeployState) -> DeployState {
+    for (ident, file) in state.files.iter_mut() {
+        let on_disk_hash = repo.hash_file(ident.name());
+        file.dirty = on_disk_hash.is_none() || on_disk_hash != file.file_hash;
+        file.file_hash = on_disk_hash;
+    }
+    state
+}
+
+/// Left in `.git/cepler_rollback/<environment>` by `Workspace::rollback` until the
+/// next `record` of the environment.
+#[derive(Serialize, Deserialize)]
+struct PendingRollback {
+    version: u32,
+    /// The version of the environment when the rollback was requested.
+    recorded_version: u32,
+}
+
+impl PendingRollback {
+    const DIR: &'static str = "cepler_rollback";
+
+    fn path(repo: &Repo, env: &str) -> PathBuf {
+        repo.git_dir().join(Self::DIR).join(env)
+    }
+
+    fn read(repo: &Repo, env: &str) -> Result<Option<Self>> {
+        let path = Self::path(repo, env);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let file = std::fs::File::open(&path).context("Couldn't open pending rollback")?;
+        Ok(Some(serde_yaml::from_reader(file)?))
+    }
+
+    fn write(&self, repo: &Repo, env: &str) -> Result<()> {
+        let path = Self::path(repo, env);
+        std::fs::create_dir_all(path.parent().unwrap())
+            .context("Couldn't create pending rollback dir")?;
+        std::fs::write(path, serde_yaml::to_vec(self)?).context("Couldn't write pending rollback")
+    }
+
+    fn clear(repo: &Repo, env: &str) -> Result<()> {
+        std::fs::remove_file(Self::path(repo, env)).context("Couldn't remove pending rollback")
+    }
+}
+
+fn upstream_patterns(env: &EnvironmentConfig) -> Vec<(String, Vec<glob::Pattern>)> {
+    env.propagated_from()
+        .iter()
+        .map(|upstream| {
+            (
+                upstream.clone(),
+                env.propagated_file_patterns(upstream).collect(),
+            )
+        })
+        .collect()
+}
+
+fn added_files(state: &DeployState) -> Vec<FileDiff> {
+    state
+        .files
+        .iter()
+        .map(|(ident, state)| FileDiff {
+            ident: ident.clone(),
+            current_state: Some(state.clone()),
+            added: true,
+        })
+        .collect()
+}
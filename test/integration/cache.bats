@@ -0,0 +1,50 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'cache'"
+  prepare_test "cache"
+  rm -rf $(cache_dir)
+}
+
+teardown_file() {
+  echo "Tearing down 'cache'"
+  reset_repo_state
+}
+
+cache_dir() {
+  echo "$(git rev-parse --git-dir)/cepler-cache"
+}
+
+@test "Caches states across invocations" {
+  cmd check -e testflight
+  [ -f $(cache_dir)/testflight.states ]
+  [ -f $(cache_dir)/last_changed ]
+  uncached=$(cmd --no-cache ls -e testflight | tail -n +2)
+  cached=$(cmd ls -e testflight | tail -n +2)
+  [ "${cached}" = "${uncached}" ]
+
+  cmd record -e testflight
+  echo "version: 2" > `fixture`/file.yml
+  git commit -am 'Second version'
+  cmd record -e testflight
+  grep "version: 2" `state testflight`
+  uncached=$(cmd --no-cache check -e staging | tail -n +2)
+  cached=$(cmd check -e staging | tail -n +2)
+  [ "${cached}" = "${uncached}" ]
+  echo "${cached}" | grep "Found new state to deploy"
+}
+
+@test "Records dirty files despite cached states" {
+  echo "version: 3" > `fixture`/file.yml
+  cmd record -e testflight --no-commit
+  grep "dirty: true" `state testflight`
+  git checkout .
+}
+
+@test "Clears the cache" {
+  cmd cache clear | grep "Cleared the state cache"
+  [ ! -d $(cache_dir) ]
+  cmd cache clear | grep "The state cache is empty"
+}
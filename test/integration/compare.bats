@@ -0,0 +1,45 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'compare'"
+  prepare_test "compare"
+}
+
+teardown_file() {
+  echo "Tearing down 'compare'"
+  reset_repo_state
+}
+
+@test "Requires recorded states" {
+  cmd record -e testflight
+  run cmd compare testflight staging
+  [ "$status" -ne 0 ]
+}
+
+@test "Reports files only in one environment" {
+  cmd record -e staging
+  output=$(cmd compare testflight staging)
+  echo "${output}" | grep "test/fixtures/compare/extra.yml only in testflight"
+  echo "${output}" | grep "1 file(s) differ, 1 identical"
+  [ -z "$(echo "${output}" | grep "file.yml differs")" ]
+}
+
+@test "Reports files with different content" {
+  sed -i 's/version: 1/version: 2/' `fixture`/file.yml
+  git commit -am 'Bump version'
+  cmd record -e testflight
+  output=$(cmd compare testflight staging --diff --color never)
+  echo "${output}" | grep "test/fixtures/compare/file.yml differs - testflight is on .*, staging is on .* from testflight"
+  echo "${output}" | grep "^-version: 2"
+  echo "${output}" | grep "^+version: 1"
+  echo "${output}" | grep "2 file(s) differ, 0 identical"
+}
+
+@test "Outputs the comparison as json" {
+  json=$(cmd -o json compare staging testflight | tail -n +2)
+  [ "$(echo "${json}" | jq -r '.environments[0]')" = "staging" ]
+  [ "$(echo "${json}" | jq -r '.files[] | select(.file == "test/fixtures/compare/file.yml") | .second.source')" = "latest" ]
+  [ "$(echo "${json}" | jq -r '.files[] | select(.file == "test/fixtures/compare/extra.yml") | .first')" = "null" ]
+}
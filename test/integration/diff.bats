@@ -0,0 +1,35 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'diff'"
+  prepare_test "diff"
+}
+
+teardown_file() {
+  echo "Tearing down 'diff'"
+  reset_repo_state
+}
+
+@test "Shows the content of added files" {
+  cmd diff -e testflight | grep "^+version: 1"
+  cmd record -e testflight
+  cmd diff -e staging | grep "^+name: app"
+}
+
+@test "Shows the content change of files" {
+  sed -i 's/version: 1/version: 2/' `fixture`/file.yml
+  git commit -am 'Bump version'
+  output=$(cmd diff -e testflight --color never)
+  echo "${output}" | grep "^-version: 1"
+  echo "${output}" | grep "^+version: 2"
+  echo "${output}" | grep "^ name: app"
+  cmd diff -e testflight --color always | grep "$(printf '\033')\[32m+version: 2"
+}
+
+@test "Summarizes the changes" {
+  cmd diff -e testflight --stat | grep "test/fixtures/diff/file.yml |    2 +-"
+  cmd diff -e testflight --stat | grep "1 file(s) changed, 1 insertion(s)(+), 1 deletion(s)(-)"
+  [ "$(cmd diff -e testflight --name-only | tail -1)" = "test/fixtures/diff/file.yml" ]
+}
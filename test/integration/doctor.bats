@@ -0,0 +1,60 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'doctor'"
+  prepare_test "doctor"
+
+  repo=${BATS_TMPDIR}/doctor
+  rm -rf ${repo}
+  git init -q -b main ${repo}
+  mkdir -p ${repo}/test/fixtures
+  cp -r `fixture` ${repo}/test/fixtures/
+  (
+    cd ${repo}
+    git config user.email "bot@cepler.dev" && git config user.name "Cepler"
+    git add -A && git commit -q -m 'Initial commit'
+    echo "version: 2" > `fixture`/file.yml
+    git commit -q -am 'Change file'
+  )
+  cmd --repo ${repo} record -e testflight
+  cmd --repo ${repo} record -e staging
+  # Squash the history as a force push would and drop the old commits
+  (
+    cd ${repo}
+    git checkout -q --orphan rewritten
+    git commit -q -m 'Squashed history'
+    git branch -D main
+    git branch -m main
+    git reflog expire --expire=now --all
+    git gc -q --prune=now
+  )
+}
+
+teardown_file() {
+  echo "Tearing down 'doctor'"
+  rm -rf ${BATS_TMPDIR}/doctor
+  reset_repo_state
+}
+
+@test "Finds dangling commits" {
+  run cmd --repo ${BATS_TMPDIR}/doctor doctor
+  [ "$status" -eq 1 ]
+  squashed=$(git -C ${BATS_TMPDIR}/doctor rev-parse --short=7 HEAD)
+  echo "$output" | grep "testflight (current) test/fixtures/doctor/file.yml: commit .* is missing - can be re-anchored to ${squashed}"
+  echo "$output" | grep "staging (current) propagated_head: commit .* is missing - can be re-anchored to ${squashed}"
+}
+
+@test "Reproduces files whose commit is gone" {
+  echo "changed" > ${BATS_TMPDIR}/doctor/`fixture`/file.yml
+  cmd --repo ${BATS_TMPDIR}/doctor reproduce -e staging
+  grep "version: 2" ${BATS_TMPDIR}/doctor/`fixture`/file.yml
+}
+
+@test "Re-anchors dangling commits" {
+  cmd --repo ${BATS_TMPDIR}/doctor doctor --fix
+  cmd --repo ${BATS_TMPDIR}/doctor doctor | grep "All commits referenced by the recorded states exist"
+  cmd --repo ${BATS_TMPDIR}/doctor check -e testflight && exit 1
+  [ "$(git -C ${BATS_TMPDIR}/doctor status --porcelain)" = "" ]
+}
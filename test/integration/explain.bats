@@ -0,0 +1,61 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'explain'"
+  prepare_test "explain"
+}
+
+teardown_file() {
+  echo "Tearing down 'explain'"
+  reset_repo_state
+}
+
+@test "Explains latest files" {
+  echo "added: true" > `fixture`/added.yml
+  git add `fixture`/added.yml
+  git commit -m 'Add added.yml'
+  output=$(cmd explain -e testflight test/fixtures/explain/added.yml)
+  echo "${output}" | grep "Matches latest glob 'test/fixtures/explain/\*.yml'"
+  echo "${output}" | grep "test/fixtures/explain/added.yml is taken from latest - \[.*\] - Add added.yml"
+  echo "${output}" | grep "is the oldest commit with the current content - the file was added"
+}
+
+@test "Explains ignored files" {
+  output=$(cmd explain -e testflight test/fixtures/explain/cepler.yml)
+  echo "${output}" | grep "Ignored as it matches 'test/fixtures/explain/cepler.yml'"
+  echo "${output}" | grep "is not part of the state of 'testflight'"
+}
+
+@test "Explains propagated and shadowed files" {
+  run cmd explain -e staging test/fixtures/explain/file.yml
+  echo "${output}" | grep "'testflight' wasn't recorded yet"
+  cmd record -e testflight
+
+  output=$(cmd explain -e staging test/fixtures/explain/file.yml)
+  echo "${output}" | grep "Matches propagated glob 'test/fixtures/explain/\*.yml' of 'testflight'"
+  echo "${output}" | grep "'testflight' propagates current .* (nothing was propagated from it yet) with the file from"
+  echo "${output}" | grep "test/fixtures/explain/file.yml is taken from testflight"
+
+  output=$(cmd explain -e staging test/fixtures/explain/override.yml)
+  echo "${output}" | grep "Shadowed by 'latest' which takes precedence over 'testflight'"
+  echo "${output}" | grep "test/fixtures/explain/override.yml is taken from latest"
+}
+
+@test "Explains modified files" {
+  sed -i 's/version: 1/version: 2/' `fixture`/file.yml
+  git commit -am 'Bump version'
+  output=$(cmd explain -e testflight ./test/fixtures/explain/file.yml)
+  echo "${output}" | grep "is taken from latest - \[.*\] - Bump version"
+  echo "${output}" | grep "the file was modified"
+}
+
+@test "Outputs the explanation as json" {
+  json=$(cmd -o json explain -e staging test/fixtures/explain/override.yml | tail -n +2)
+  [ "$(echo "${json}" | jq -r '.source')" = "latest" ]
+  [ "$(echo "${json}" | jq -r '.upstreams[0].shadowed_by')" = "latest" ]
+  [ "$(echo "${json}" | jq -r '.upstreams[0].state.reason')" = "first-propagation" ]
+  json=$(cmd -o json explain -e testflight test/fixtures/explain/file.yml | tail -n +2)
+  [ "$(echo "${json}" | jq -r '.change')" = "modified" ]
+}
@@ -0,0 +1,54 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'fan_in'"
+  prepare_test "fan_in"
+}
+
+teardown_file() {
+  echo "Tearing down 'fan_in'"
+  reset_repo_state
+}
+
+@test "Requires all upstream environments to be deployed" {
+  cmd record -e staging-eu
+
+  run cmd check -e production
+  [ "$status" -eq 1 ]
+
+  cmd record -e staging-us
+  cmd check -e production
+}
+
+@test "Records which upstream each file came from" {
+  cmd record -e production
+
+  grep '{staging-eu}/test/fixtures/fan_in/eu.yml' `state production`
+  grep '{staging-us}/test/fixtures/fan_in/us.yml' `state production`
+  grep 'staging-eu:' `state production`
+  grep 'staging-us:' `state production`
+}
+
+@test "Rejects the environment while upstream environments disagree on shared files" {
+  echo "shared_new: {}" > `fixture`/shared.yml
+  git commit -am 'Update shared.yml'
+
+  cmd record -e staging-eu
+
+  run cmd check -e production
+  [ "$status" -eq 1 ]
+  echo "${output}" | grep "Upstream environments 'staging-eu' and 'staging-us' of 'production' disagree on the content of 'test/fixtures/fan_in/shared.yml'"
+
+  run cmd prepare -e production
+  [ "$status" -eq 1 ]
+
+  run cmd status
+  [ "$status" -eq 0 ]
+  echo "${output}" | grep "WARNING Upstream environments 'staging-eu' and 'staging-us' of 'production' disagree"
+
+  cmd record -e staging-us
+  cmd prepare -e production
+  grep 'shared_new' `fixture`/shared.yml
+}
@@ -0,0 +1,31 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'first_parent'"
+  prepare_test "first_parent"
+}
+
+teardown_file() {
+  echo "Tearing down 'first_parent'"
+  reset_repo_state
+  git branch -D first_parent-feature || true
+}
+
+@test "Attributes changes merged from a branch to the merge commit" {
+  git checkout -b first_parent-feature
+  echo "version: 2" > `fixture`/file.yml
+  git commit -am 'Change file on feature branch'
+  feature=$(git rev-parse HEAD)
+  git checkout first_parent
+  git commit --allow-empty -m 'Mainline change'
+  git merge --no-ff -m 'Merge feature' first_parent-feature
+  merge=$(git rev-parse HEAD)
+  git commit --allow-empty -m 'Unrelated change'
+
+  cmd check -e testflight | grep "trigger commit ${merge}"
+  cmd record -e testflight
+  grep "from_commit: ${merge}" `state testflight`
+  [ -z "$(grep ${feature} `state testflight`)" ]
+}
@@ -28,3 +28,16 @@ teardown_file() {
   files=$(find . -name '*.yml' | wc -l)
   [ "${files}" -eq 4 ]
 }
+
+@test "Unrelated commits don't move the trigger commit" {
+  echo "version: 2" > `fixture`/subdir/staging.yml
+  git commit -am 'Change staging file'
+  trigger=$(git rev-parse HEAD)
+  echo "unrelated" > `fixture`/../unrelated.txt
+  git add `fixture`/../unrelated.txt
+  git commit -m 'Unrelated change'
+  echo "unrelated again" > `fixture`/../unrelated.txt
+  git commit -am 'Another unrelated change'
+
+  cmd check -e staging | grep "trigger commit ${trigger}"
+}
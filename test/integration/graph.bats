@@ -0,0 +1,42 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'graph'"
+  prepare_test "graph"
+}
+
+teardown_file() {
+  echo "Tearing down 'graph'"
+  reset_repo_state
+}
+
+@test "Outputs the pipeline as DOT" {
+  output=$(cmd graph)
+  echo "${output}" | grep '^digraph cepler {'
+  echo "${output}" | grep '"testflight" \[label="testflight\\nnot recorded\\npending changes"\];'
+  echo "${output}" | grep '"staging" \[label="staging\\nnot recorded\\nwaiting for testflight"\];'
+  echo "${output}" | grep '"testflight" -> "staging" \[label="test/fixtures/graph/\*.yml"\];'
+}
+
+@test "Annotates recorded environments" {
+  cmd record -e testflight
+  output=$(cmd graph --format dot)
+  echo "${output}" | grep '"testflight" \[label="testflight\\nv1 \[.......\]\\nup to date"\];'
+  echo "${output}" | grep '"staging" \[label="staging\\nnot recorded\\npending changes"\];'
+}
+
+@test "Outputs the pipeline as Mermaid" {
+  output=$(cmd graph --format mermaid)
+  echo "${output}" | grep '^graph LR'
+  echo "${output}" | grep '  env0\["testflight<br>v1 \[.......\]<br>up to date"\]'
+  echo "${output}" | grep '  env0 -->|"test/fixtures/graph/\*.yml"| env1'
+}
+
+@test "Outputs the graph as json" {
+  json=$(cmd -o json graph | tail -n +2)
+  [ "$(echo "${json}" | jq -r '.nodes[0].version')" = "1" ]
+  [ "$(echo "${json}" | jq -r '.nodes[1].pending')" = "true" ]
+  [ "$(echo "${json}" | jq -r '.edges[0].files[0]')" = "test/fixtures/graph/*.yml" ]
+}
@@ -0,0 +1,47 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'history'"
+  prepare_test "history"
+}
+
+teardown_file() {
+  echo "Tearing down 'history'"
+  reset_repo_state
+}
+
+@test "Lists every recorded version" {
+  cmd record -e testflight
+  echo "field: changed" > `fixture`/file.yml
+  git commit -am 'Change history file'
+  cmd record -e testflight
+
+  cmd history -e testflight | grep "Version 2 recorded at .* - trigger commit $(git rev-parse --short=7 HEAD~1)"
+  cmd history -e testflight | grep "File test/fixtures/history/file.yml changed \[.*\] - Change history file"
+  cmd history -e testflight | grep "Version 1 recorded at"
+}
+
+@test "Limits the listed versions" {
+  [ "$(cmd history -e testflight --limit 1 | grep -c '^Version')" -eq 1 ]
+  [ "$(cmd history -e testflight --since 2000-01-01 | grep -c '^Version')" -eq 2 ]
+  [ "$(cmd history -e testflight --since 2999-01-01 | grep -c '^Version')" -eq 0 ]
+}
+
+@test "Emits json" {
+  output=$(cmd --output json history -e testflight --limit 1 | tail -1)
+  echo "${output}" | grep '"version":2},"recorded_at":"'
+  echo "${output}" | grep "\"state_commit\":\"$(git rev-parse HEAD)\""
+}
+
+@test "Keeps listing versions recorded after an older one" {
+  echo "field: changed again" > `fixture`/file.yml
+  git commit -am 'Change history file again'
+  cmd record -e testflight
+  GIT_COMMITTER_DATE="2001-01-01T00:00:00" git commit --amend --no-edit
+
+  versions=$(cmd history -e testflight --since 2005-01-01 | grep '^Version')
+  [ "$(echo "${versions}" | grep -c '^Version')" -eq 2 ]
+  [ -z "$(echo "${versions}" | grep '^Version 3')" ]
+}
@@ -0,0 +1,74 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'history_bound'"
+  prepare_test "history_bound"
+}
+
+teardown_file() {
+  echo "Tearing down 'history_bound'"
+  reset_repo_state
+}
+
+@test "Uses the current commit as trigger past the history bound" {
+  echo "version: 2" > `fixture`/file.yml
+  git commit -am 'Change file'
+  trigger=$(git rev-parse HEAD)
+  for i in 1 2 3; do
+    git commit --allow-empty -m "Unrelated change ${i}"
+  done
+
+  run cmd check -e unbounded
+  echo "$output" | grep "trigger commit ${trigger}"
+  [ -z "$(echo "$output" | grep WARNING)" ]
+
+  run cmd check -e bounded
+  echo "$output" | grep "trigger commit $(git rev-parse HEAD)"
+  echo "$output" | grep "WARNING Reached the history bound of 'bounded'"
+}
+
+@test "Includes the warnings in the json output" {
+  json=$(cmd -o json check -e unbounded 2>/dev/null | tail -1)
+  [ -z "$(echo "$json" | grep '"warnings"')" ]
+
+  json=$(cmd -o json check -e bounded 2>/dev/null | tail -1)
+  echo "$json" | grep "\"warnings\":\[\"Reached the history bound of 'bounded'"
+}
+
+@test "Finds the trigger exactly at the history bound" {
+  echo "version: 3" > `fixture`/file.yml
+  git commit -am 'Change file again'
+  trigger=$(git rev-parse HEAD)
+  git commit --allow-empty -m "Unrelated change 4"
+  git commit --allow-empty -m "Unrelated change 5"
+
+  run cmd check -e bounded
+  echo "$output" | grep "trigger commit ${trigger}"
+  [ -z "$(echo "$output" | grep WARNING)" ]
+}
+
+@test "Finds the trigger exactly at the history age bound" {
+  echo "version: 4" > `fixture`/file.yml
+  GIT_COMMITTER_DATE="2000-01-01T00:00:00" git commit -am 'Old change'
+  echo "version: 5" > `fixture`/file.yml
+  git commit -am 'Recent change'
+  trigger=$(git rev-parse HEAD)
+
+  run cmd check -e unbounded
+  echo "$output" | grep "trigger commit ${trigger}"
+  [ -z "$(echo "$output" | grep WARNING)" ]
+}
+
+@test "Includes the warnings in the json output when there is nothing new" {
+  cmd record -e bounded
+  git commit --allow-empty -m "Unrelated change 6"
+  git commit --allow-empty -m "Unrelated change 7"
+  git commit --allow-empty -m "Unrelated change 8"
+
+  run cmd -o json check -e bounded
+  [ "$status" -eq 2 ]
+  json=$(cmd -o json check -e bounded 2>/dev/null | tail -1)
+  echo "$json" | grep "^{\"state_id\":null,\"diffs\":\[\],\"warnings\":\[\"Reached the history bound of 'bounded'"
+}
@@ -0,0 +1,62 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'impact'"
+  prepare_test "impact"
+}
+
+teardown_file() {
+  echo "Tearing down 'impact'"
+  reset_repo_state
+}
+
+@test "Reports environments deploying immediately" {
+  echo "testflight_new: {}" > `fixture`/testflight.yml
+  git commit -am 'Update testflight.yml'
+  output=$(cmd impact HEAD)
+  echo "${output}" | grep "1 file(s) changed in"
+  echo "${output}" | grep "'testflight' deploys immediately: test/fixtures/impact/testflight.yml"
+  echo "${output}" | grep "'staging' is not affected"
+  echo "${output}" | grep "'production' is not affected"
+}
+
+@test "Reports environments receiving changes via propagation" {
+  sed -i 's/version: 1/version: 2/' `fixture`/file.yml
+  git commit -am 'Bump version'
+  output=$(cmd impact HEAD~1..HEAD)
+  echo "${output}" | grep "'testflight' deploys immediately: test/fixtures/impact/file.yml"
+  echo "${output}" | grep "'staging' receives via propagation from 'testflight': test/fixtures/impact/file.yml"
+  echo "${output}" | grep "'production' receives via propagation from 'staging': test/fixtures/impact/file.yml"
+
+  echo "staging_new: {}" > `fixture`/staging.yml
+  git commit -am 'Update staging.yml'
+  output=$(cmd impact HEAD~3...HEAD)
+  echo "${output}" | grep "3 file(s) changed in"
+  echo "${output}" | grep "'staging' deploys immediately: test/fixtures/impact/staging.yml"
+  echo "${output}" | grep "'testflight' deploys immediately: test/fixtures/impact/file.yml, test/fixtures/impact/testflight.yml"
+}
+
+@test "Ignores the config file" {
+  echo "# comment" >> `fixture`/cepler.yml
+  git commit -am 'Update config'
+  cmd impact HEAD | grep "0 file(s) changed in"
+}
+
+@test "Outputs a markdown report" {
+  output=$(cmd impact HEAD~3..HEAD~2 --format markdown)
+  echo "${output}" | grep '^### Deployment impact of `.*\.\..*`'
+  echo "${output}" | grep '^| testflight | deploys immediately | `test/fixtures/impact/file.yml` |'
+  echo "${output}" | grep '^| production | propagated from staging | `test/fixtures/impact/file.yml` |'
+}
+
+@test "Outputs the impact as json" {
+  json=$(cmd -o json impact HEAD~3..HEAD~2 | tail -n +2)
+  [ "$(echo "${json}" | jq -r '.environments[1].name')" = "staging" ]
+  [ "$(echo "${json}" | jq -r '.environments[1].propagated.testflight[0]')" = "test/fixtures/impact/file.yml" ]
+  [ "$(echo "${json}" | jq -r '.environments[2].immediate | length')" = "0" ]
+  run cmd impact does-not-exist..HEAD
+  [ "$status" -eq 1 ]
+  echo "${output}" | grep "Couldn't resolve revision 'does-not-exist..HEAD'"
+}
@@ -0,0 +1,39 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'json_output'"
+  prepare_test "json_output"
+}
+
+teardown_file() {
+  echo "Tearing down 'json_output'"
+  reset_repo_state
+}
+
+@test "ls lists files with their provenance" {
+  output=$(cmd --output json ls -e testflight | tail -1)
+  [ "${output}" = '{"files":[{"file":"test/fixtures/json_output/file.yml","from":"latest"}]}' ]
+}
+
+@test "check emits the state id and diffs" {
+  output=$(cmd --output json check -e testflight | tail -1)
+  echo "${output}" | grep "\"state_id\":{\"head_commit\":\"$(git rev-parse HEAD)\",\"version\":1}"
+  echo "${output}" | grep '"file":"test/fixtures/json_output/file.yml","from":"latest","change":"added"'
+}
+
+@test "record emits the version and state file" {
+  output=$(cmd --output json record -e testflight | tail -1)
+  echo "${output}" | grep '"version":1'
+  echo "${output}" | grep "\"state_file\":\"$(state testflight)\""
+
+  run cmd --output json check -e testflight
+  [ "$status" -eq 2 ]
+  [ "${lines[1]}" = '{"state_id":null,"diffs":[]}' ]
+}
+
+@test "prepare emits propagated files" {
+  output=$(cmd --output json prepare -e staging | tail -1)
+  echo "${output}" | grep '"file":"test/fixtures/json_output/file.yml","from":"testflight"'
+}
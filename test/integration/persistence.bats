@@ -0,0 +1,54 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'persistence'"
+  prepare_test "persistence"
+}
+
+teardown_file() {
+  echo "Tearing down 'persistence'"
+  rm -f $(lock_file)
+  reset_repo_state
+}
+
+lock_file() {
+  echo "$(git rev-parse --git-dir)/cepler-$(fixture | tr / -)-.cepler-default.lock"
+}
+
+@test "Refuses to record while the state is locked" {
+  echo "$$" > $(lock_file)
+  run cmd record -e testflight
+  [ "$status" -eq 1 ]
+  echo "$output" | grep "locked by another cepler process"
+  [ ! -f `state testflight` ]
+
+  rm $(lock_file)
+  cmd record -e testflight
+  grep "version: 1" `state testflight`
+  [ ! -f $(lock_file) ]
+}
+
+@test "Takes over the lock of a process that is gone" {
+  sleep 0 &
+  pid=$!
+  wait ${pid}
+  echo "${pid}" > $(lock_file)
+  cmd record -e staging
+  grep "version: 1" `state staging`
+  [ ! -f $(lock_file) ]
+  git reset --hard HEAD~1
+}
+
+@test "Only rewrites the recorded environment" {
+  cmd record -e staging
+  echo "version: 2" > `fixture`/file.yml
+  git commit -am 'Second version'
+  staging=$(git log -1 --format=%H -- `state staging`)
+
+  cmd record -e testflight
+  [ "$(git log -1 --format=%H -- `state staging`)" = "${staging}" ]
+  grep "version: 2" `state testflight`
+  [ -z "$(ls -A `fixture`/.cepler/default | grep -v '\.state$')" ]
+}
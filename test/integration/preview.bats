@@ -0,0 +1,49 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'preview'"
+  prepare_test "preview"
+}
+
+teardown_file() {
+  echo "Tearing down 'preview'"
+  reset_repo_state
+}
+
+@test "Previews the first record" {
+  cmd preview -e testflight | grep "Recording would deploy version 1 of 'testflight'"
+  cmd record -e testflight
+  output=$(cmd preview -e staging)
+  echo "${output}" | grep "Propagating current of 'testflight' .* - nothing was propagated from it yet"
+  echo "${output}" | grep "File test/fixtures/preview/file.yml was added"
+  cmd record -e staging
+  cmd preview -e staging | grep "Nothing new to deploy"
+}
+
+@test "Explains the selection from the propagation queue" {
+  sed -i 's/version: 1/version: 2/' `fixture`/file.yml
+  git commit -am 'Bump version to 2'
+  cmd record -e testflight
+  sed -i 's/version: 2/version: 3/' `fixture`/file.yml
+  git commit -am 'Bump version to 3'
+  cmd record -e testflight
+
+  before=$(git status --porcelain)
+  cmd preview -e staging | grep "Propagating queue\[0\] of 'testflight' .* - oldest queued state that changes a propagated file"
+  cmd --ignore-queue preview -e staging | grep "Propagating current of 'testflight' .* - the propagation queue is ignored"
+  [ "$(git status --porcelain)" = "${before}" ]
+  grep "version: 3" `fixture`/file.yml
+}
+
+@test "Outputs the preview as json" {
+  json=$(cmd -o json preview -e staging | tail -n +2)
+  [ "$(echo "${json}" | jq -r '.upstreams[0].state')" = "queue[0]" ]
+  [ "$(echo "${json}" | jq -r '.upstreams[0].reason')" = "queue-entry" ]
+  [ "$(echo "${json}" | jq -r '.state_id.version')" = "2" ]
+  cmd prepare -e staging
+  cmd record -e staging
+  json=$(cmd -o json preview -e staging | tail -n +2)
+  [ "$(echo "${json}" | jq -r '.upstreams[0].reason')" = "no-queued-changes" ]
+}
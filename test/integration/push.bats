@@ -0,0 +1,84 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'push'"
+  prepare_test "push"
+
+  remote=${BATS_TMPDIR}/push-remote.git
+  rm -rf ${remote} ${BATS_TMPDIR}/push-a ${BATS_TMPDIR}/push-b
+  git init -q --bare ${remote}
+  git init -q -b main ${BATS_TMPDIR}/push-a
+  mkdir -p ${BATS_TMPDIR}/push-a/test/fixtures
+  cp -r `fixture` ${BATS_TMPDIR}/push-a/test/fixtures/
+  (
+    cd ${BATS_TMPDIR}/push-a
+    git config user.email "bot@cepler.dev" && git config user.name "Cepler"
+    git add -A && git commit -q -m 'Initial commit'
+    git remote add origin ${remote}
+    git push -q origin main
+  )
+  git clone -q -b main ${remote} ${BATS_TMPDIR}/push-b
+}
+
+teardown_file() {
+  echo "Tearing down 'push'"
+  reset_repo_state
+}
+
+push() {
+  cmd --repo ${BATS_TMPDIR}/$1 record -e $2 --reset-head --push --git-url origin --git-private-key none
+}
+
+push_ref_store() {
+  cmd --repo ${BATS_TMPDIR}/$1 --state-store $2 record -e $3 --reset-head --push --git-url origin --git-private-key none
+}
+
+remote_state() {
+  git --git-dir ${BATS_TMPDIR}/push-remote.git show main:`state $1`
+}
+
+@test "Rebases onto states recorded concurrently for other environments" {
+  push push-b smoketest
+  push push-a testflight
+
+  remote_state smoketest | grep "version: 1"
+  remote_state testflight | grep "version: 1"
+}
+
+@test "Refuses to overwrite a state recorded concurrently" {
+  git -C ${BATS_TMPDIR}/push-b pull -q --rebase origin main
+  push push-b testflight
+
+  run push push-a testflight
+  [ "$status" -eq 1 ]
+  echo "$output" | grep "'testflight' was recorded concurrently: expected version 1 on the remote but found version 2"
+  remote_state testflight | grep "version: 2"
+}
+
+@test "Replays every unpushed state of the state branch" {
+  push_ref_store push-b branch staging
+  cmd --repo ${BATS_TMPDIR}/push-a --state-store branch record -e smoketest
+  push_ref_store push-a branch testflight
+
+  for env in staging smoketest testflight; do
+    git --git-dir ${BATS_TMPDIR}/push-remote.git show cepler-state:`state ${env}` | grep "version: 1"
+  done
+}
+
+@test "Replays every unpushed note" {
+  push_ref_store push-b notes staging
+  cmd --repo ${BATS_TMPDIR}/push-a --state-store notes record -e smoketest
+  push_ref_store push-a notes testflight
+
+  notes=$(git --git-dir ${BATS_TMPDIR}/push-remote.git log --format=%b -p refs/notes/cepler)
+  for env in staging smoketest testflight; do
+    echo "${notes}" | grep "`state ${env}`"
+  done
+}
+
+@test "Rejects an invalid number of retries" {
+  run cmd --repo ${BATS_TMPDIR}/push-a record -e testflight --push-retries many
+  [ "$status" -eq 1 ]
+}
@@ -0,0 +1,37 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'repo_path'"
+  prepare_test "repo_path"
+}
+
+teardown_file() {
+  echo "Tearing down 'repo_path'"
+  reset_repo_state
+}
+
+@test "Resolves paths from a subdirectory of the repo" {
+  cd `fixture`
+  cmd check -e testflight
+  files="$(cmd ls -e testflight | wc -l)"
+  [ "${files}" -eq 2 ]
+}
+
+@test "Operates on --repo from outside the repo" {
+  cd ${BATS_TMPDIR}
+  cmd --repo ${REPO_ROOT} record -e testflight
+  cd ${REPO_ROOT}
+  [ -f `state testflight` ]
+  [ "$(git log -1 --format=%s)" = "ci(cepler): Updated 'testflight' state" ]
+}
+
+@test "Prepares --repo from outside the repo" {
+  cd ${BATS_TMPDIR}
+  cmd --repo ${REPO_ROOT} prepare -e staging --force-clean
+  cd ${REPO_ROOT}
+  [ -f `fixture`/file.yml ]
+  cd ${BATS_TMPDIR}
+  [ "$(cmd --repo ${REPO_ROOT} latest -e testflight | tail -1)" = "$(git -C ${REPO_ROOT} rev-parse HEAD~1)" ]
+}
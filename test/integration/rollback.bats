@@ -0,0 +1,84 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'rollback'"
+  prepare_test "rollback"
+}
+
+teardown_file() {
+  echo "Tearing down 'rollback'"
+  reset_repo_state
+}
+
+@test "Reproduces an earlier version" {
+  cmd record -e testflight
+  echo "version: 2" > `fixture`/file.yml
+  git commit -am 'Second version'
+  cmd record -e testflight
+
+  cmd reproduce -e testflight --version 1
+  grep "version: 1" `fixture`/file.yml
+  git checkout `fixture`/file.yml
+
+  run cmd reproduce -e testflight --version 5
+  [ "$status" -eq 1 ]
+}
+
+@test "Records a rollback as a new version" {
+  cmd rollback -e testflight --to-version 1
+  grep "version: 1" `fixture`/file.yml
+  cmd record -e testflight --reset-head
+
+  grep "version: 3" `state testflight`
+  grep "rollback_of: 1" `state testflight`
+  grep "version: 2" `fixture`/file.yml
+  cmd history -e testflight --limit 1 | grep "Version 3 (rollback to version 1)"
+}
+
+@test "Downstream propagates the rolled back state" {
+  cmd prepare -e staging
+  grep "version: 1" `fixture`/file.yml
+}
+
+@test "Keeps a pending rollback per environment" {
+  cmd record -e staging --reset-head
+  cmd rollback -e staging --to-version 1
+  cmd rollback -e testflight --to-version 2
+  cmd record -e testflight --reset-head
+  grep "rollback_of: 2" `state testflight`
+  [ -f $(git rev-parse --git-dir)/cepler_rollback/staging ]
+
+  cmd reproduce -e staging --version 1
+  cmd record -e staging --reset-head
+  grep "rollback_of: 1" `state staging`
+  [ -z "$(grep 'dirty: true' `state staging`)" ]
+}
+
+@test "Records files changed after the rollback as dirty" {
+  cmd rollback -e testflight --to-version 1
+  echo "version: 9" > `fixture`/file.yml
+  cmd record -e testflight --reset-head
+  grep "dirty: true" `state testflight`
+}
+
+@test "Discards the rollback when the environment was recorded elsewhere" {
+  cmd rollback -e testflight --to-version 1
+  git checkout `fixture`
+  other=${BATS_TMPDIR}/rollback-other
+  rm -rf ${other}
+  git clone -q -b $(git branch --show-current) file://${REPO_ROOT} ${other}
+  (
+    cd ${other}
+    git config user.email "bot@cepler.dev" && git config user.name "Cepler"
+    cmd record -e testflight
+  )
+  git pull -q --ff-only ${other} $(git branch --show-current)
+  rm -rf ${other}
+
+  run cmd record -e testflight
+  echo "$output" | grep "WARNING Discarding the rollback of 'testflight' to version 1 as version 6 was recorded since"
+  [ ! -f $(git rev-parse --git-dir)/cepler_rollback/testflight ]
+  [ -z "$(cmd history -e testflight --limit 1 | tail -n +2 | grep 'rollback')" ]
+}
@@ -0,0 +1,54 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'shallow'"
+  prepare_test "shallow"
+}
+
+teardown_file() {
+  echo "Tearing down 'shallow'"
+  kill $(read_value "server_pid") || true
+  rm -rf ${BATS_TMPDIR}/shallow ${BATS_TMPDIR}/shallow-remotes ${BATS_TMPDIR}/shallow-clone
+  reset_repo_state
+}
+
+@test "Treats the shallow boundary as the first commit" {
+  echo "version: 2" > `fixture`/file.yml
+  git commit -am 'Change file'
+  trigger=$(git rev-parse HEAD)
+  git commit --allow-empty -m 'Unrelated change 1'
+  boundary=$(git rev-parse HEAD)
+  git commit --allow-empty -m 'Unrelated change 2'
+
+  cmd check -e testflight | grep "trigger commit ${trigger}"
+
+  rm -rf ${BATS_TMPDIR}/shallow
+  git clone --depth 2 -b shallow file://${REPO_ROOT} ${BATS_TMPDIR}/shallow
+  run cmd --repo ${BATS_TMPDIR}/shallow check -e testflight
+  echo "$output" | grep "trigger commit ${boundary}"
+  echo "$output" | grep "WARNING Reached the boundary of the shallow clone at ${boundary:0:7}"
+  [ ! -d ${BATS_TMPDIR}/shallow/.git/cepler-cache ]
+}
+
+@test "Clones shallow with --depth" {
+  root=${BATS_TMPDIR}/shallow-remotes
+  rm -rf ${root} ${BATS_TMPDIR}/shallow-clone
+  mkdir -p ${root}
+  git clone -q --bare -b shallow file://${REPO_ROOT} ${root}/repo.git
+  port=$(( 20000 + RANDOM % 10000 ))
+  ${REPO_ROOT}/test/integration/git-http-server.py ${root} ${port} cepler s3cret >/dev/null 2>&1 &
+  cache_value "server_pid" $!
+  for i in $(seq 1 50); do
+    curl -s -o /dev/null http://127.0.0.1:${port}/ && break
+    sleep 0.1
+  done
+
+  boundary=$(git rev-parse HEAD~1)
+  run cmd --clone ${BATS_TMPDIR}/shallow-clone --git-url http://localhost:${port}/repo.git \
+    --git-username cepler --git-password s3cret --git-branch shallow --depth 2 check -e testflight
+  echo "$output" | grep "trigger commit ${boundary}"
+  echo "$output" | grep "WARNING Reached the boundary of the shallow clone at ${boundary:0:7}"
+  [ "$(git -C ${BATS_TMPDIR}/shallow-clone rev-list --count HEAD)" = "2" ]
+}
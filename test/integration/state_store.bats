@@ -0,0 +1,53 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'state_store'"
+  prepare_test "state_store"
+}
+
+teardown_file() {
+  echo "Tearing down 'state_store'"
+  git branch -D cepler-state || true
+  git update-ref -d refs/notes/cepler || true
+  reset_repo_state
+}
+
+@test "Records state on a separate branch" {
+  head=$(git rev-parse HEAD)
+  cmd --state-store branch record -e testflight
+  [ "$(git rev-parse HEAD)" = "${head}" ]
+  [ ! -f `state testflight` ]
+  git show cepler-state:`state testflight` | grep "version: 1"
+
+  cmd --state-store branch status | grep -E "^testflight +1 "
+  cmd --state-store branch history -e testflight | grep "Version 1"
+  cmd --state-store branch check -e staging
+}
+
+@test "Propagates from the state branch" {
+  cmd --state-store branch record -e staging
+  git show cepler-state:`state staging` | grep "version: 1"
+  run cmd --state-store branch check -e staging
+  [ "$status" -eq 2 ]
+}
+
+@test "Records state as git notes" {
+  head=$(git rev-parse HEAD)
+  CEPLER_STATE_STORE=notes cmd record -e testflight
+  [ "$(git rev-parse HEAD)" = "${head}" ]
+  git notes --ref cepler list ${head}
+  git notes --ref cepler show ${head} | grep "version: 1"
+
+  CEPLER_STATE_STORE=notes cmd check -e staging
+  CEPLER_STATE_STORE=notes cmd record -e staging
+  git notes --ref cepler show ${head} | grep "staging.state"
+}
+
+@test "Ref stores refuse to record without committing" {
+  echo "version: 2" > `fixture`/file.yml
+  git commit -am 'Second version'
+  run cmd --state-store branch record -e testflight --no-commit
+  [ "$status" -eq 1 ]
+}
@@ -0,0 +1,35 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'status'"
+  prepare_test "status"
+}
+
+teardown_file() {
+  echo "Tearing down 'status'"
+  reset_repo_state
+}
+
+@test "Shows environments that were never recorded" {
+  cmd status | grep -E "^testflight +- +- +- +- +0 +yes$"
+  cmd status | grep -E "^staging +- +- +- +- +0 +blocked \(testflight not deployed\)$"
+}
+
+@test "Shows recorded versions and propagated heads" {
+  cmd record -e testflight
+  head=$(git rev-parse --short=7 HEAD~1)
+  cmd status | grep -E "^testflight +1 +${head} +- +no +0 +no$"
+  cmd status | grep -E "^staging +- +- +- +- +0 +yes$"
+
+  cmd record -e staging
+  cmd status | grep -E "^staging +1 +[0-9a-f]{7} +testflight@${head} +no +0 +no$"
+}
+
+@test "Emits json" {
+  output=$(cmd --output json status | tail -1)
+  echo "${output}" | grep '"name":"testflight","version":1'
+  echo "${output}" | grep '"name":"staging","version":1,'
+  echo "${output}" | grep '"pending":false,"blocked_by":null}]$'
+}
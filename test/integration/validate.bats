@@ -0,0 +1,42 @@
+#!/usr/bin/env bats
+
+load "helpers"
+
+setup_file() {
+  echo "Preparing 'validate'"
+  prepare_test "validate"
+}
+
+teardown_file() {
+  echo "Tearing down 'validate'"
+  reset_repo_state
+}
+
+@test "Accepts a valid config" {
+  cmd validate
+}
+
+@test "Reports problems with line numbers" {
+  cat <<EOF > `config`
+environments:
+  testflight:
+    passed: staging
+    latest:
+    - "[test/fixtures/validate/file.yml"
+    - test/fixtures/validate/missing.yml
+  staging:
+    passed: testflight
+    propagated:
+    - test/fixtures/validate/file.yml
+    latest:
+    - test/fixtures/validate/*.yml
+EOF
+  git commit -am 'Break cepler.yml'
+
+  run cmd validate
+  [ "$status" -eq 1 ]
+  echo "$output" | grep "cepler.yml:5: Environment 'testflight' has malformed glob pattern"
+  echo "$output" | grep "cepler.yml:6: Environment 'testflight' pattern 'test/fixtures/validate/missing.yml' doesn't match any file"
+  echo "$output" | grep "cepler.yml:8: Cycle in 'passed' chain: staging -> testflight -> staging"
+  echo "$output" | grep "cepler.yml:12: Environment 'staging' latest pattern 'test/fixtures/validate/\*.yml' shadows"
+}